@@ -1,81 +1,458 @@
-use candid::{CandidType, Deserialize};
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::cash::Rate;
+use canister_common::cycles::{self, CyclesStatus};
+use canister_common::history::{self, Bucket, HistoryStore, Resolution};
+use canister_common::interval::{self, IntervalBounds, IntervalChange, IntervalState};
+use canister_common::jobs::{
+    self, Job, JobStatus, JobStore, MissedTickPolicy, Schedule, TimerStatus,
+};
+use canister_common::transfers::{self, Transaction};
+use canister_common::{admin, leaderboard, Error};
 use ic_cdk_macros::*;
 use serde::Serialize;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 type UserStore = BTreeMap<usize, User>;
 
+// Version of the state saved by `pre_upgrade`, bumped when a field changes meaning.
+// Fields are optional, so that state saved before a field was added still decodes.
+const STATE_VERSION: u32 = 1;
+
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+    version: u32,
+    jobs: Option<JobStore<Task>>,
+    users: Option<UserStore>,
+    transactions: Option<Vec<Transaction>>,
+    next_payout_id: Option<u64>,
+    admins: Option<Vec<Principal>>,
+    interval_settings: Option<IntervalState>,
+    accrual_cursor: Option<AccrualCursor>,
+    queued_ticks: Option<u64>,
+    cycles_reserve: Option<u128>,
+    history: Option<HistoryStore>,
+}
+
 thread_local! {
     static USERS: RefCell<UserStore> = RefCell::default();
-    static INTERVAL_IN_SECONDS: RefCell<u64> = RefCell::default();
-    static TIMERS: RefCell<ic_cdk_timers::TimerId> = RefCell::default();
+    static NEXT_PAYOUT_ID: RefCell<u64> = RefCell::default();
+    static ACCRUAL_CURSOR: RefCell<Option<AccrualCursor>> = RefCell::default();
+    // Ticks that arrived during the running accrual pass, applied by the next one
+    static QUEUED_TICKS: RefCell<u64> = RefCell::default();
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 struct User {
+    id: usize,
+    owner: Principal, // Only the owner may transfer the user's cash
     username: String,
-    cash: usize,
+    cash: u128,
+    cap: Option<u128>, // Cash stops accruing once it reaches the cap
+    rate: Rate,
 }
 
-#[derive(CandidType, Deserialize, Serialize)]
-struct Error {
-    message: String,
+impl User {
+    // Changes the cash, keeping the leaderboard and the history in step
+    fn set_cash(&mut self, cash: u128, now: u64) {
+        leaderboard::update(self.id, Some(self.cash), cash);
+        history::record(self.id, cash, now);
+        self.cash = cash;
+    }
+
+    // Cash stops accruing at the cap, or saturates at the largest amount without one
+    fn limit(&self) -> u128 {
+        self.cap.unwrap_or(u128::MAX)
+    }
+
+    // The cash after crediting `amount`, which stops at the limit just like accrual
+    fn credit(&self, amount: u128) -> u128 {
+        if self.cash >= self.limit() {
+            return self.cash;
+        }
+        self.cash.saturating_add(amount).min(self.limit())
+    }
 }
 
-#[ic_cdk::init]
-fn init() {
-    let seconds = 3;
-    INTERVAL_IN_SECONDS.with(|interval_ref| {
-        interval_ref.replace(seconds);
-    });
+const DEFAULT_JOB: &str = "accrue_cash";
+const DEFAULT_INTERVAL_IN_SECONDS: u64 = 3;
 
-    let interval = std::time::Duration::from_secs(seconds);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
-        USERS.with(|users| {
-            let mut users = users.borrow_mut();
-            for user in users.values_mut() {
-                user.cash += 1; // Increment cash for each user
+// Work that can be scheduled through the job registry
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+enum Task {
+    AccrueCash,
+    Payout { user_ids: Vec<usize>, amount: u128 }, // Credits `amount` to every listed user
+}
+
+impl jobs::Task for Task {
+    fn run(&self, job: &str, ticks: u64) {
+        match self {
+            Task::AccrueCash => accrue_cash(job, ticks),
+            Task::Payout { user_ids, amount } => pay_out(job, user_ids, *amount),
+        }
+    }
+}
+
+const PAYOUT_JOB_PREFIX: &str = "payout_";
+
+fn pay_out(job: &str, user_ids: &[usize], amount: u128) {
+    let now = ic_cdk::api::time();
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        for user_id in user_ids {
+            match users.get_mut(user_id) {
+                Some(user) => user.set_cash(user.credit(amount), now),
+                None => jobs::report_error::<Task>(
+                    job,
+                    format!("Skipping payout to unknown user {}", user_id),
+                ),
             }
+        }
+    });
+}
+
+// Users credited per message, so that a pass stays within the instruction limit
+// however many users there are
+const ACCRUAL_BATCH_SIZE: usize = 1_000;
+
+// Ticks queued behind a running pass are capped, so that a stalled pass cannot
+// pile up an unbounded credit
+const MAX_QUEUED_TICKS: u64 = 1_000;
+
+// Where an accrual pass spanning several messages continues
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct AccrualCursor {
+    job: String,
+    next_user_id: usize,
+    ticks: u64, // Accruals applied to every user in this pass
+}
+
+fn accrue_cash(job: &str, ticks: u64) {
+    if ACCRUAL_CURSOR.with(|cursor| cursor.borrow().is_some()) {
+        QUEUED_TICKS.with(|queued| {
+            let mut queued = queued.borrow_mut();
+            *queued = queued.saturating_add(ticks).min(MAX_QUEUED_TICKS);
         });
+        return;
+    }
+    run_accrual_batch(AccrualCursor {
+        job: job.to_string(),
+        next_user_id: 0,
+        ticks,
     });
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(timer_id);
+}
+
+// Credits the next batch of users and returns where the pass continues. Once a pass
+// is through, the ticks that arrived meanwhile start the next one.
+fn accrue_cash_batch(cursor: AccrualCursor, now: u64) -> Option<AccrualCursor> {
+    let next_user_id = USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let mut batch = users.range_mut(cursor.next_user_id..);
+        for (_, user) in batch.by_ref().take(ACCRUAL_BATCH_SIZE) {
+            // Apply each user's own rate
+            user.set_cash(
+                user.rate.compound(user.cash, cursor.ticks, user.limit()),
+                now,
+            );
+        }
+        batch.next().map(|(id, _)| *id)
     });
+
+    let next = match next_user_id {
+        Some(next_user_id) => Some(AccrualCursor {
+            next_user_id,
+            ..cursor
+        }),
+        None => match QUEUED_TICKS.with(|queued| queued.take()) {
+            0 => None,
+            ticks => Some(AccrualCursor {
+                job: cursor.job,
+                next_user_id: 0,
+                ticks,
+            }),
+        },
+    };
+    ACCRUAL_CURSOR.with(|cursor_ref| cursor_ref.replace(next.clone()));
+    next
+}
+
+// A zero-delay timer runs each further batch in a fresh message with its own
+// instruction limit
+fn run_accrual_batch(cursor: AccrualCursor) {
+    if let Some(next) = accrue_cash_batch(cursor, ic_cdk::api::time()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run_accrual_batch(next));
+    }
+}
+
+#[ic_cdk::init]
+fn init() {
+    let schedule = Schedule::Interval {
+        seconds: DEFAULT_INTERVAL_IN_SECONDS,
+    };
+    jobs::set(DEFAULT_JOB.to_string(), Task::AccrueCash, schedule).unwrap();
+    // Cash should keep pace with wall-clock time, so missed ticks are paid out late
+    jobs::set_missed_tick_policy::<Task>(DEFAULT_JOB, MissedTickPolicy::CatchUp).unwrap();
+}
+
+fn save_state() -> StableState {
+    StableState {
+        version: STATE_VERSION,
+        jobs: Some(jobs::save()),
+        users: Some(USERS.with(|users| users.borrow().clone())),
+        transactions: Some(transfers::save()),
+        next_payout_id: Some(NEXT_PAYOUT_ID.with(|id_ref| *id_ref.borrow())),
+        admins: Some(admin::list()),
+        interval_settings: Some(interval::save()),
+        accrual_cursor: ACCRUAL_CURSOR.with(|cursor| cursor.borrow().clone()),
+        queued_ticks: Some(QUEUED_TICKS.with(|queued| *queued.borrow())),
+        cycles_reserve: Some(cycles::reserve()),
+        history: Some(history::save()),
+    }
+}
+
+// Restores the saved state, handing back the jobs to re-arm and the accrual pass
+// to resume
+fn restore_state(state: StableState) -> (JobStore<Task>, Option<AccrualCursor>) {
+    let users = state.users.unwrap_or_default();
+    leaderboard::rebuild(users.values().map(|user| (user.id, user.cash)));
+    USERS.with(|users_ref| *users_ref.borrow_mut() = users);
+    transfers::restore(state.transactions.unwrap_or_default());
+    NEXT_PAYOUT_ID.with(|id_ref| id_ref.replace(state.next_payout_id.unwrap_or_default()));
+    admin::restore(state.admins.unwrap_or_default());
+    interval::restore(state.interval_settings.unwrap_or_default());
+    if let Some(reserve) = state.cycles_reserve {
+        cycles::set_reserve(reserve);
+    }
+    history::restore(state.history.unwrap_or_default());
+    QUEUED_TICKS.with(|queued| queued.replace(state.queued_ticks.unwrap_or_default()));
+    ACCRUAL_CURSOR.with(|cursor_ref| cursor_ref.replace(state.accrual_cursor.clone()));
+    (state.jobs.unwrap_or_default(), state.accrual_cursor)
+}
+
+// The state saved by `pre_upgrade`. Stable memory is grown in whole pages, so the
+// encoded state is followed by zeros.
+fn decode_state(bytes: &[u8]) -> candid::Result<StableState> {
+    IDLDeserialize::new(bytes)?.get_value()
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((save_state(),)).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // The previous version saved nothing, so there is nothing to lose by starting over
+    if ic_cdk::api::stable::stable_size() == 0 {
+        init();
+        return;
+    }
+    // Trapping rolls the upgrade back rather than dropping the state this version cannot read
+    let state = decode_state(&ic_cdk::api::stable::stable_bytes())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode the saved state: {}", e)));
+    if state.version > STATE_VERSION {
+        ic_cdk::trap(&format!(
+            "State version {} is newer than {}",
+            state.version, STATE_VERSION
+        ));
+    }
+    let (jobs, accrual_cursor) = restore_state(state);
+
+    // Timers do not survive an upgrade, so re-arm the active jobs on their previous
+    // schedule, including pending payouts
+    jobs::restore(jobs);
+
+    // Finish an accrual pass that was interrupted by the upgrade
+    if let Some(cursor) = accrual_cursor {
+        run_accrual_batch(cursor);
+    }
+}
+
+fn current_interval() -> Option<u64> {
+    match jobs::get::<Task>(DEFAULT_JOB).map(|job| job.schedule) {
+        Some(Schedule::Interval { seconds }) => Some(seconds),
+        _ => None,
+    }
 }
 
 #[query]
 fn get_interval() -> Result<u64, Error> {
-    INTERVAL_IN_SECONDS.with(|interval_ref| Ok(interval_ref.borrow().clone()))
+    current_interval().ok_or_else(|| Error {
+        message: format!("Job {} does not run on an interval", DEFAULT_JOB),
+    })
 }
 
+// The interval is checked against the configured bounds by the job registry
 #[update]
 fn set_interval(seconds: u64) -> Result<u64, Error> {
-    TIMERS.with(|timers_ref| {
-        let timer_id = timers_ref.borrow().clone();
-        ic_cdk_timers::clear_timer(timer_id);
-    });
+    admin::ensure_admin()?;
 
-    let interval = std::time::Duration::from_secs(seconds);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    let new_timer_id = ic_cdk_timers::set_timer_interval(interval, || {
-        USERS.with(|users| {
-            let mut users = users.borrow_mut();
-            for user in users.values_mut() {
-                user.cash += 1; // Increment cash for each user
-            }
+    let previous_seconds = current_interval();
+    let schedule = Schedule::Interval { seconds };
+    jobs::set(DEFAULT_JOB.to_string(), Task::AccrueCash, schedule)?;
+    interval::record(previous_seconds, seconds);
+
+    Ok(seconds)
+}
+
+#[query]
+fn timer_status() -> Result<TimerStatus, Error> {
+    jobs::status::<Task>(DEFAULT_JOB)
+}
+
+#[update]
+fn pause_timer() -> Result<TimerStatus, Error> {
+    admin::ensure_admin()?;
+    jobs::pause::<Task>(DEFAULT_JOB)?;
+    jobs::status::<Task>(DEFAULT_JOB)
+}
+
+#[update]
+fn resume_timer() -> Result<TimerStatus, Error> {
+    admin::ensure_admin()?;
+    jobs::resume::<Task>(DEFAULT_JOB)?;
+    jobs::status::<Task>(DEFAULT_JOB)
+}
+
+#[query]
+fn get_interval_bounds() -> IntervalBounds {
+    interval::bounds()
+}
+
+#[update]
+fn set_interval_bounds(bounds: IntervalBounds) -> Result<IntervalBounds, Error> {
+    admin::ensure_admin()?;
+    interval::set_bounds(bounds)
+}
+
+#[query]
+fn cycles_status() -> CyclesStatus {
+    cycles::status()
+}
+
+// Jobs suspended by the cycles guard resume right away if the new reserve is met
+#[update]
+fn set_cycles_reserve(reserve: u128) -> Result<CyclesStatus, Error> {
+    admin::ensure_admin()?;
+    cycles::set_reserve(reserve);
+    jobs::resume_suspended::<Task>();
+    Ok(cycles::status())
+}
+
+#[query]
+fn get_interval_changes() -> Vec<IntervalChange> {
+    interval::changes()
+}
+
+#[update]
+fn add_admin(principal: Principal) -> Result<Vec<Principal>, Error> {
+    admin::ensure_controller()?;
+    Ok(admin::add(principal))
+}
+
+#[update]
+fn remove_admin(principal: Principal) -> Result<Vec<Principal>, Error> {
+    admin::ensure_controller()?;
+    Ok(admin::remove(&principal))
+}
+
+#[query]
+fn list_admins() -> Vec<Principal> {
+    admin::list()
+}
+
+#[update]
+fn register_job(name: String, task: Task, schedule: Schedule) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+    jobs::register(name, task, schedule)
+}
+
+#[query]
+fn list_jobs() -> Vec<Job<Task>> {
+    jobs::list()
+}
+
+#[update]
+fn pause_job(name: String) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+    jobs::pause(&name)
+}
+
+#[update]
+fn resume_job(name: String) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+    jobs::resume(&name)
+}
+
+#[update]
+fn cancel_job(name: String) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+    jobs::cancel(&name)
+}
+
+#[update]
+fn set_missed_tick_policy(name: String, policy: MissedTickPolicy) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+    jobs::set_missed_tick_policy(&name, policy)
+}
+
+#[update]
+fn schedule_payout(user_ids: Vec<usize>, amount: u128, at: u64) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+
+    if user_ids.is_empty() || amount == 0 {
+        return Err(Error {
+            message: "A payout needs at least one user and an amount greater than 0".to_string(),
         });
-    });
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(new_timer_id);
-    });
+    }
+    if at <= ic_cdk::api::time() {
+        return Err(Error {
+            message: "A payout must be scheduled in the future".to_string(),
+        });
+    }
+    USERS.with(|users| {
+        let users = users.borrow();
+        match user_ids.iter().find(|user_id| !users.contains_key(user_id)) {
+            Some(user_id) => Err(Error {
+                message: format!("User {} not found", user_id),
+            }),
+            None => Ok(()),
+        }
+    })?;
 
-    INTERVAL_IN_SECONDS.with(|seconds_ref| {
-        seconds_ref.replace(seconds);
-    });
+    let id = NEXT_PAYOUT_ID.with(|id_ref| id_ref.replace_with(|id| *id + 1));
+    let name = format!("{}{}", PAYOUT_JOB_PREFIX, id);
+    jobs::register(
+        name,
+        Task::Payout { user_ids, amount },
+        Schedule::Once { at },
+    )
+}
 
-    Ok(seconds)
+#[query]
+fn list_scheduled_payouts() -> Vec<Job<Task>> {
+    jobs::list()
+        .into_iter()
+        .filter(|job| matches!(job.task, Task::Payout { .. }) && job.status != JobStatus::Completed)
+        .collect()
+}
+
+#[update]
+fn cancel_payout(name: String) -> Result<Job<Task>, Error> {
+    admin::ensure_admin()?;
+
+    match jobs::get::<Task>(&name) {
+        Some(job)
+            if matches!(job.task, Task::Payout { .. }) && job.status != JobStatus::Completed =>
+        {
+            jobs::cancel(&name)
+        }
+        _ => Err(Error {
+            message: format!("Scheduled payout {} not found", name),
+        }),
+    }
 }
 
 #[update]
@@ -85,9 +462,15 @@ async fn add_user(username: String) -> Result<String, Error> {
 
         let new_id = users.len() + 1; // Simple way to generate a new ID
         let user = User {
+            id: new_id,
+            owner: ic_cdk::caller(),
             username: username.clone(),
             cash: 0,
+            cap: None,
+            rate: Rate::default(),
         };
+        leaderboard::update(new_id, None, user.cash);
+        history::record(new_id, user.cash, ic_cdk::api::time());
         users.insert(new_id, user);
 
         let usernames: Vec<User> = users.values().cloned().collect();
@@ -96,6 +479,95 @@ async fn add_user(username: String) -> Result<String, Error> {
     })
 }
 
+#[update]
+fn set_rate(user_id: usize, rate: Rate) -> Result<String, Error> {
+    // Rates decide how much cash is minted, so only admins may change them
+    admin::ensure_admin()?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.rate = rate;
+                let res = serde_json::to_string(&user).unwrap();
+                Ok(res)
+            }
+            None => Err(Error {
+                message: format!("User {} not found", user_id),
+            }),
+        }
+    })
+}
+
+#[update]
+fn set_cap(user_id: usize, cap: Option<u128>) -> Result<String, Error> {
+    admin::ensure_admin()?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.cap = cap;
+                let res = serde_json::to_string(&user).unwrap();
+                Ok(res)
+            }
+            None => Err(Error {
+                message: format!("User {} not found", user_id),
+            }),
+        }
+    })
+}
+
+#[update]
+fn transfer(
+    from_id: usize,
+    to_id: usize,
+    amount: u128,
+    memo: Option<String>,
+    created_at_time: Option<u64>,
+) -> Result<u64, Error> {
+    let now = ic_cdk::api::time();
+    let transaction = transfers::prepare(from_id, to_id, amount, memo, created_at_time, now)?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let from = users.get(&from_id).ok_or_else(|| Error {
+            message: format!("User {} not found", from_id),
+        })?;
+        if from.owner != ic_cdk::caller() {
+            return Err(Error {
+                message: format!("Caller does not own user {}", from_id),
+            });
+        }
+        let to = users.get(&to_id).ok_or_else(|| Error {
+            message: format!("User {} not found", to_id),
+        })?;
+
+        let (from_cash, to_cash) = transfers::apply(&transaction, from.cash, to.cash)?;
+        if let Some(from) = users.get_mut(&from_id) {
+            from.set_cash(from_cash, now);
+        }
+        if let Some(to) = users.get_mut(&to_id) {
+            to.set_cash(to_cash, now);
+        }
+        Ok(())
+    })?;
+
+    Ok(transfers::record(transaction))
+}
+
+// A user's transfers, newest first, in pages of at most `limit` (up to 100)
+#[query]
+fn get_transactions(user_id: usize, cursor: Option<u64>, limit: usize) -> Result<String, String> {
+    let page = transfers::page(user_id, cursor, limit);
+    match serde_json::to_string(&page) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(format!("Failed to serialize transactions: {}", e)),
+    }
+}
+
 #[query]
 fn get_users() -> Result<String, String> {
     USERS.with(|users| {
@@ -107,6 +579,37 @@ fn get_users() -> Result<String, String> {
     })
 }
 
+// Users ranked by cash, highest first
+#[query]
+fn leaderboard(limit: usize) -> Result<String, String> {
+    USERS.with(|users| {
+        let users = users.borrow();
+
+        let ranking: Vec<&User> = leaderboard::top(limit)
+            .iter()
+            .filter_map(|id| users.get(id))
+            .collect();
+        let res = serde_json::to_string(&ranking).unwrap();
+        Ok(res)
+    })
+}
+
+// How a user's cash changed between `from` and `to`, in nanoseconds since the epoch
+#[query]
+fn balance_history(
+    user_id: usize,
+    from: u64,
+    to: u64,
+    resolution: Resolution,
+) -> Result<Vec<Bucket>, Error> {
+    if !USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        return Err(Error {
+            message: format!("User {} not found", user_id),
+        });
+    }
+    history::range(user_id, from, to, resolution)
+}
+
 #[query]
 fn search_users(query: String) -> Result<String, String> {
     USERS.with(|users| {
@@ -129,3 +632,163 @@ fn search_users(query: String) -> Result<String, String> {
 
 // Enable Candid export
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_ticking_after_an_upgrade() {
+        let user = User {
+            id: 1,
+            owner: Principal::anonymous(),
+            username: "alice".to_string(),
+            cash: 5,
+            cap: None,
+            rate: Rate::default(),
+        };
+        USERS.with(|users| users.borrow_mut().insert(1, user));
+        transfers::record(transfers::prepare(1, 2, 3, None, None, 0).unwrap());
+        admin::add(Principal::anonymous());
+        let job = Job {
+            name: DEFAULT_JOB.to_string(),
+            task: Task::AccrueCash,
+            schedule: Schedule::Interval { seconds: 7 },
+            status: JobStatus::Active,
+            created_at: 0,
+            last_run_at: Some(30),
+            next_run_at: Some(100),
+            run_count: 4,
+            last_error: None,
+            missed_tick_policy: MissedTickPolicy::CatchUp,
+            missed_ticks: 2,
+            drift_nanos: 0,
+        };
+        let cursor = AccrualCursor {
+            job: DEFAULT_JOB.to_string(),
+            next_user_id: 1,
+            ticks: 1,
+        };
+        ACCRUAL_CURSOR.with(|cursor_ref| cursor_ref.replace(Some(cursor)));
+        QUEUED_TICKS.with(|queued| queued.replace(2));
+        cycles::set_reserve(5);
+        history::record(1, 5, 0);
+        let mut state = save_state();
+        state.jobs = Some(JobStore::from([(job.name.clone(), job)]));
+        let mut bytes = candid::encode_args((state,)).unwrap();
+        bytes.resize(64 * 1024, 0);
+
+        USERS.with(|users| users.borrow_mut().clear());
+        transfers::restore(Vec::new());
+        admin::restore(Vec::new());
+        ACCRUAL_CURSOR.with(|cursor_ref| cursor_ref.take());
+        QUEUED_TICKS.with(|queued| queued.take());
+        cycles::set_reserve(0);
+        history::restore(HistoryStore::new());
+        let (jobs, accrual_cursor) = restore_state(decode_state(&bytes).unwrap());
+
+        assert_eq!(USERS.with(|users| users.borrow()[&1].cash), 5);
+        assert_eq!(leaderboard::top(10), [1]);
+        assert_eq!(transfers::page(2, None, 10).transactions.len(), 1);
+        assert_eq!(admin::list(), vec![Principal::anonymous()]);
+        assert_eq!(cycles::reserve(), 5);
+        assert_eq!(history::amount_at(1, 0), Some(5));
+        let job = &jobs[DEFAULT_JOB];
+        assert!(job.status == JobStatus::Active);
+        assert!(job.schedule == Schedule::Interval { seconds: 7 });
+        assert_eq!(job.run_count, 4);
+        assert!(job.missed_tick_policy == MissedTickPolicy::CatchUp);
+        assert_eq!(job.missed_ticks, 2);
+        // The re-armed job keeps its pending run, or runs right away if that has passed
+        assert_eq!(jobs::due_at(job, 40).unwrap(), 100);
+        assert_eq!(jobs::due_at(job, 140).unwrap(), 140);
+        // The interrupted accrual pass resumes where it stopped, with the ticks queued behind it
+        assert_eq!(accrual_cursor.map(|cursor| cursor.next_user_id), Some(1));
+        assert_eq!(QUEUED_TICKS.with(|queued| *queued.borrow()), 2);
+    }
+
+    #[test]
+    fn credits_every_tick_once_across_batches() {
+        let interest = Rate {
+            amount: 1,
+            interest_bps: Some(100),
+        };
+        USERS.with(|users| {
+            let mut users = users.borrow_mut();
+            for id in 0..2 * ACCRUAL_BATCH_SIZE + 500 {
+                let rate = if id % 2 == 0 {
+                    Rate::default()
+                } else {
+                    interest.clone()
+                };
+                let user = User {
+                    id,
+                    owner: Principal::anonymous(),
+                    username: format!("user{}", id),
+                    cash: 1_000,
+                    cap: None,
+                    rate,
+                };
+                users.insert(id, user);
+            }
+        });
+
+        let mut next = accrue_cash_batch(
+            AccrualCursor {
+                job: DEFAULT_JOB.to_string(),
+                next_user_id: 0,
+                ticks: 1,
+            },
+            0,
+        );
+        let mut passes = vec![];
+        while let Some(cursor) = next {
+            if cursor.next_user_id == 0 {
+                passes.push(cursor.ticks);
+            }
+            // Two ticks fire while the first pass is still running
+            if passes.is_empty() && cursor.next_user_id == ACCRUAL_BATCH_SIZE {
+                accrue_cash(DEFAULT_JOB, 1);
+                accrue_cash(DEFAULT_JOB, 1);
+            }
+            next = accrue_cash_batch(cursor, 0);
+        }
+
+        // The queued ticks are applied together by a single follow-up pass
+        assert_eq!(passes, vec![2]);
+        assert!(ACCRUAL_CURSOR.with(|cursor| cursor.borrow().is_none()));
+        assert_eq!(QUEUED_TICKS.with(|queued| *queued.borrow()), 0);
+        USERS.with(|users| {
+            for user in users.borrow().values() {
+                let expected = user.rate.compound(1_000, 3, u128::MAX);
+                assert_eq!(user.cash, expected, "user {}", user.id);
+            }
+        });
+    }
+
+    #[test]
+    fn pays_out_up_to_the_cap() {
+        let mut user = User {
+            id: 1,
+            owner: Principal::anonymous(),
+            username: "alice".to_string(),
+            cash: u128::MAX - 1,
+            cap: None,
+            rate: Rate::default(),
+        };
+        assert_eq!(user.credit(5), u128::MAX);
+
+        user.cash = 8;
+        user.cap = Some(10);
+        assert_eq!(user.credit(5), 10);
+        // Lowering the cap below the cash does not take any cash away
+        user.cap = Some(4);
+        assert_eq!(user.credit(5), 8);
+    }
+
+    #[test]
+    fn refuses_state_it_cannot_read() {
+        assert!(decode_state(&[0; 64 * 1024]).is_err());
+        assert!(decode_state(b"DIDL\x00\x01\x7d\x07").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Longest ranking a single query returns
+pub const MAX_LIMIT: usize = 100;
+
+// Users by descending projected cash, ties going to the lower id
+type Ranking = BTreeSet<(Reverse<u128>, usize)>;
+
+// Where a user is ranked
+#[derive(Clone, Copy, PartialEq)]
+pub enum Entry {
+    // A user with a fixed rate of `amount` per tick and no cap. Users sharing the same
+    // amount gain the same cash on every tick, so their order never changes as cash
+    // accrues: they are ranked by the cash they would hold on the last representable tick.
+    Linear { amount: u64, projected: u128 },
+    // A user whose order may change with time (compounding, capped, or whose projection
+    // overflows). Their balances are computed when ranking.
+    Other,
+}
+
+thread_local! {
+    // Linear users by amount. Kept up to date on every change so that queries only walk
+    // the top of each ranking.
+    static LINEAR: RefCell<BTreeMap<u64, Ranking>> = RefCell::default();
+    static OTHERS: RefCell<BTreeSet<usize>> = RefCell::default();
+    static ENTRIES: RefCell<BTreeMap<usize, Entry>> = RefCell::default();
+}
+
+fn remove(id: usize, entry: Entry) {
+    match entry {
+        Entry::Linear { amount, projected } => LINEAR.with(|linear| {
+            let mut linear = linear.borrow_mut();
+            if let Some(users) = linear.get_mut(&amount) {
+                users.remove(&(Reverse(projected), id));
+                if users.is_empty() {
+                    linear.remove(&amount);
+                }
+            }
+        }),
+        Entry::Other => {
+            OTHERS.with(|others| others.borrow_mut().remove(&id));
+        }
+    }
+}
+
+// Moves a user to `entry`, adding a new user
+pub fn update(id: usize, entry: Entry) {
+    let previous = ENTRIES.with(|entries| entries.borrow_mut().insert(id, entry));
+    if previous == Some(entry) {
+        return;
+    }
+    if let Some(previous) = previous {
+        remove(id, previous);
+    }
+    match entry {
+        Entry::Linear { amount, projected } => LINEAR.with(|linear| {
+            linear
+                .borrow_mut()
+                .entry(amount)
+                .or_default()
+                .insert((Reverse(projected), id));
+        }),
+        Entry::Other => {
+            OTHERS.with(|others| others.borrow_mut().insert(id));
+        }
+    }
+}
+
+// Ids of the users that may be among the `limit` highest ranked (capped at `MAX_LIMIT`):
+// the top of every group of linear users and all the other users. The caller ranks them
+// by their current balances, so a query settles at most `limit` users per distinct fixed
+// rate plus every compounding or capped user. Rates and caps are set by controllers only,
+// which keeps both numbers small next to the number of users.
+pub fn candidates(limit: usize) -> Vec<usize> {
+    let limit = limit.min(MAX_LIMIT);
+    let mut ids: Vec<usize> = LINEAR.with(|linear| {
+        linear
+            .borrow()
+            .values()
+            .flat_map(|users| users.iter().take(limit).map(|(_, id)| *id))
+            .collect()
+    });
+    OTHERS.with(|others| ids.extend(others.borrow().iter()));
+    ids
+}
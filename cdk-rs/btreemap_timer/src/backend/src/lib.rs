@@ -1,38 +1,108 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::cash::Rate;
+use canister_common::history::{Bucket, Resolution};
+use canister_common::{admin, history, transfers, Error};
 use ic_cdk_macros::*;
 use serde::Serialize;
 use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
 
+mod leaderboard;
+
 type UserStore = BTreeMap<usize, User>;
 
 thread_local! {
     static USERS: RefCell<UserStore> = RefCell::default();
+    static ACCRUAL_STARTED_AT: RefCell<u64> = RefCell::default();
 }
 
+// Cash is credited once per accrual period, counted from ACCRUAL_STARTED_AT
+const ACCRUAL_PERIOD_NANOS: u64 = 1_000_000_000;
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 struct User {
+    id: usize,
+    owner: Principal, // Only the owner may transfer the user's cash
     username: String,
-    cash: usize,
+    cash: u128,        // Cash settled as of `last_settled_at`
+    cap: Option<u128>, // Cash stops accruing once it reaches the cap
+    rate: Rate,
+    last_settled_at: u64, // Nanoseconds since the epoch
 }
 
-#[derive(CandidType, Deserialize, Serialize)]
-struct Error {
-    message: String,
+// Number of accrual periods that have fully elapsed at `time`
+fn accrual_period(time: u64) -> u64 {
+    let started_at = ACCRUAL_STARTED_AT.with(|started_ref| *started_ref.borrow());
+    time.saturating_sub(started_at) / ACCRUAL_PERIOD_NANOS
+}
+
+impl User {
+    // Cash held at `now`, derived from the settled cash and the periods elapsed since
+    fn balance(&self, now: u64) -> u128 {
+        let periods = accrual_period(now) - accrual_period(self.last_settled_at);
+        self.rate.compound(self.cash, periods, self.limit())
+    }
+
+    // Cash stops accruing at the cap, or saturates at the largest amount without one
+    fn limit(&self) -> u128 {
+        self.cap.unwrap_or(u128::MAX)
+    }
+
+    // Where the user is ranked, see `leaderboard::Entry`
+    fn ranking(&self) -> leaderboard::Entry {
+        let linear = matches!(self.rate.interest_bps, None | Some(0)) && self.cap.is_none();
+        let remaining = u64::MAX - accrual_period(self.last_settled_at);
+        let projected = (self.rate.amount as u128)
+            .checked_mul(remaining as u128)
+            .and_then(|amount| self.cash.checked_add(amount));
+        match projected {
+            Some(projected) if linear => leaderboard::Entry::Linear {
+                amount: self.rate.amount,
+                projected,
+            },
+            _ => leaderboard::Entry::Other,
+        }
+    }
+
+    // Updates the user's place in the leaderboard after a change to its cash, rate or cap
+    fn reindex(&self) {
+        leaderboard::update(self.id, self.ranking());
+    }
+
+    // Changes the stored cash, keeping the leaderboard and the history in step
+    fn set_cash(&mut self, cash: u128, now: u64) {
+        history::record(self.id, cash, now);
+        self.cash = cash;
+        self.reindex();
+    }
+
+    // Folds the accrued cash into `cash` so that the rate can be changed safely
+    fn settle(&mut self, now: u64) {
+        let cash = self.balance(now);
+        self.last_settled_at = now;
+        self.set_cash(cash, now);
+    }
+
+    // A copy settled to `now`, leaving the stored user and the leaderboard as they are
+    fn settled(&self, now: u64) -> User {
+        User {
+            cash: self.balance(now),
+            last_settled_at: now,
+            ..self.clone()
+        }
+    }
 }
 
 #[ic_cdk::init]
 fn init() {
-    let interval = std::time::Duration::from_secs(1);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    ic_cdk_timers::set_timer_interval(interval, || {
-        USERS.with(|users| {
-            let mut users = users.borrow_mut();
-            for user in users.values_mut() {
-                user.cash += 1; // Increment cash for each user
-            }
-        });
-    });
+    // Balances are computed lazily on read, so no per-user work is scheduled here
+    let started_at = ic_cdk::api::time();
+    ACCRUAL_STARTED_AT.with(|started_ref| started_ref.replace(started_at));
+    ic_cdk::println!(
+        "Accruing cash every {:?}",
+        std::time::Duration::from_nanos(ACCRUAL_PERIOD_NANOS)
+    );
 }
 
 #[update]
@@ -41,39 +111,188 @@ async fn add_user(username: String) -> Result<String, Error> {
         let mut users = users.borrow_mut();
 
         let new_id = users.len() + 1; // Simple way to generate a new ID
+        let now = ic_cdk::api::time();
         let user = User {
+            id: new_id,
+            owner: ic_cdk::caller(),
             username: username.clone(),
             cash: 0,
+            cap: None,
+            rate: Rate::default(),
+            last_settled_at: now,
         };
+        user.reindex();
+        history::record(new_id, user.cash, now);
         users.insert(new_id, user);
 
-        let usernames: Vec<User> = users.values().cloned().collect();
+        let usernames: Vec<User> = users.values().map(|user| user.settled(now)).collect();
         let res = serde_json::to_string(&usernames).unwrap();
         Ok(res)
     })
 }
 
+#[update]
+fn set_rate(user_id: usize, rate: Rate) -> Result<String, Error> {
+    // Rates decide how much cash is minted, so only controllers may change them
+    admin::ensure_controller()?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                user.settle(ic_cdk::api::time());
+                user.rate = rate;
+                user.reindex();
+                let res = serde_json::to_string(&user).unwrap();
+                Ok(res)
+            }
+            None => Err(Error {
+                message: format!("User {} not found", user_id),
+            }),
+        }
+    })
+}
+
+#[update]
+fn set_cap(user_id: usize, cap: Option<u128>) -> Result<String, Error> {
+    admin::ensure_controller()?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        match users.get_mut(&user_id) {
+            Some(user) => {
+                // Cash accrued so far is kept, even above the new cap
+                user.settle(ic_cdk::api::time());
+                user.cap = cap;
+                user.reindex();
+                let res = serde_json::to_string(&user).unwrap();
+                Ok(res)
+            }
+            None => Err(Error {
+                message: format!("User {} not found", user_id),
+            }),
+        }
+    })
+}
+
+#[update]
+fn transfer(
+    from_id: usize,
+    to_id: usize,
+    amount: u128,
+    memo: Option<String>,
+    created_at_time: Option<u64>,
+) -> Result<u64, Error> {
+    let now = ic_cdk::api::time();
+    let transaction = transfers::prepare(from_id, to_id, amount, memo, created_at_time, now)?;
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        // Settle both sides first so that the transfer moves up-to-date balances
+        for user_id in [from_id, to_id] {
+            if let Some(user) = users.get_mut(&user_id) {
+                user.settle(now);
+            }
+        }
+        let from = users.get(&from_id).ok_or_else(|| Error {
+            message: format!("User {} not found", from_id),
+        })?;
+        if from.owner != ic_cdk::caller() {
+            return Err(Error {
+                message: format!("Caller does not own user {}", from_id),
+            });
+        }
+        let to = users.get(&to_id).ok_or_else(|| Error {
+            message: format!("User {} not found", to_id),
+        })?;
+
+        let (from_cash, to_cash) = transfers::apply(&transaction, from.cash, to.cash)?;
+        if let Some(from) = users.get_mut(&from_id) {
+            from.set_cash(from_cash, now);
+        }
+        if let Some(to) = users.get_mut(&to_id) {
+            to.set_cash(to_cash, now);
+        }
+        Ok(())
+    })?;
+
+    Ok(transfers::record(transaction))
+}
+
+// A user's transfers, newest first, in pages of at most `limit` (up to 100)
+#[query]
+fn get_transactions(user_id: usize, cursor: Option<u64>, limit: usize) -> Result<String, String> {
+    let page = transfers::page(user_id, cursor, limit);
+    match serde_json::to_string(&page) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(format!("Failed to serialize transactions: {}", e)),
+    }
+}
+
 #[query]
 fn get_users() -> Result<String, String> {
     USERS.with(|users| {
         let users = users.borrow();
 
-        let usernames: Vec<User> = users.values().cloned().collect();
+        let now = ic_cdk::api::time();
+        let usernames: Vec<User> = users.values().map(|user| user.settled(now)).collect();
         let res = serde_json::to_string(&usernames).unwrap();
         Ok(res)
     })
 }
 
+// The `limit` users with the most cash at `now`, settled to `now`, ties going to the lower id
+fn rank(users: &UserStore, limit: usize, now: u64) -> Vec<User> {
+    let mut ranking: Vec<User> = leaderboard::candidates(limit)
+        .iter()
+        .filter_map(|id| users.get(id))
+        .map(|user| user.settled(now))
+        .collect();
+    ranking.sort_by_key(|user| (Reverse(user.cash), user.id));
+    ranking.truncate(limit.min(leaderboard::MAX_LIMIT));
+    ranking
+}
+
+#[query]
+fn leaderboard(limit: usize) -> Result<String, String> {
+    USERS.with(|users| {
+        let ranking = rank(&users.borrow(), limit, ic_cdk::api::time());
+        let res = serde_json::to_string(&ranking).unwrap();
+        Ok(res)
+    })
+}
+
+// How a user's cash changed between `from` and `to`, in nanoseconds since the epoch.
+// Cash accrues lazily, so it is only recorded when settled or transferred.
+#[query]
+fn balance_history(
+    user_id: usize,
+    from: u64,
+    to: u64,
+    resolution: Resolution,
+) -> Result<Vec<Bucket>, Error> {
+    if !USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        return Err(Error {
+            message: format!("User {} not found", user_id),
+        });
+    }
+    history::range(user_id, from, to, resolution)
+}
+
 #[query]
 fn search_users(query: String) -> Result<String, String> {
     USERS.with(|users| {
         let users = users.borrow();
 
         // Filter the users whose usernames contain the query string
+        let now = ic_cdk::api::time();
         let filtered_usernames: Vec<User> = users
             .values()
             .filter(|user| user.username.to_lowercase().contains(&query.to_lowercase()))
-            .cloned()
+            .map(|user| user.settled(now))
             .collect();
 
         // Convert the filtered list of usernames to a JSON string
@@ -86,3 +305,121 @@ fn search_users(query: String) -> Result<String, String> {
 
 // Enable Candid export
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(cash: u128, rate: Rate) -> User {
+        User {
+            id: 1,
+            owner: Principal::anonymous(),
+            username: "alice".to_string(),
+            cash,
+            cap: None,
+            rate,
+            last_settled_at: 0,
+        }
+    }
+
+    fn ranked(
+        id: usize,
+        cash: u128,
+        amount: u64,
+        interest_bps: Option<u32>,
+        cap: Option<u128>,
+    ) -> User {
+        let user = User {
+            id,
+            owner: Principal::anonymous(),
+            username: format!("user {}", id),
+            cash,
+            cap,
+            rate: Rate {
+                amount,
+                interest_bps,
+            },
+            last_settled_at: 0,
+        };
+        user.reindex();
+        user
+    }
+
+    fn store(users: impl IntoIterator<Item = User>) -> UserStore {
+        users.into_iter().map(|user| (user.id, user)).collect()
+    }
+
+    fn ranked_ids(users: &UserStore, limit: usize, seconds: u64) -> Vec<usize> {
+        rank(users, limit, seconds * ACCRUAL_PERIOD_NANOS)
+            .iter()
+            .map(|user| user.id)
+            .collect()
+    }
+
+    #[test]
+    fn ranks_by_current_cash() {
+        let users = store([
+            ranked(1, 100, 1, None, None),
+            ranked(2, 0, 5, None, None),
+            ranked(3, 90, 1, None, None),
+            ranked(4, 0, 1, Some(100), Some(120)),
+            ranked(5, 60, 0, None, None),
+        ]);
+
+        assert_eq!(ranked_ids(&users, 10, 0), [1, 3, 5, 2, 4]);
+        // 110, 50, 100, 10 and 60 after 10 seconds
+        assert_eq!(ranked_ids(&users, 3, 10), [1, 3, 5]);
+        // The faster rate overtakes, the capped user stops at 120
+        assert_eq!(ranked_ids(&users, 3, 50), [2, 1, 3]);
+        assert_eq!(ranked_ids(&users, 10, 1_000), [2, 1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn reads_the_top_of_each_rate_and_every_other_user() {
+        // The worst case: many users on each of several fixed rates, plus users
+        // whose order can change over time
+        let mut users = Vec::new();
+        for amount in 1..=3 {
+            for n in 0..2 * leaderboard::MAX_LIMIT {
+                let id = amount as usize * 1_000 + n;
+                users.push(ranked(id, n as u128, amount, None, None));
+            }
+        }
+        for id in 0..50 {
+            users.push(ranked(id, 0, 1, Some(100), None));
+            users.push(ranked(100 + id, 0, 1, None, Some(1_000)));
+        }
+        let users = store(users);
+
+        let candidates = leaderboard::candidates(usize::MAX);
+        assert_eq!(candidates.len(), 3 * leaderboard::MAX_LIMIT + 100);
+        assert_eq!(leaderboard::candidates(10).len(), 3 * 10 + 100);
+
+        // Reading only those still finds the true top users
+        let now = 10 * ACCRUAL_PERIOD_NANOS;
+        let mut expected: Vec<User> = users.values().map(|user| user.settled(now)).collect();
+        expected.sort_by_key(|user| (Reverse(user.cash), user.id));
+        let expected: Vec<usize> = expected.iter().take(10).map(|user| user.id).collect();
+        assert_eq!(ranked_ids(&users, 10, 10), expected);
+    }
+
+    #[test]
+    fn caps_an_overflowing_user() {
+        let rate = Rate {
+            amount: 7,
+            interest_bps: Some(10_000),
+        };
+        let mut overflowing = user(u128::MAX / 4, rate);
+        overflowing.settle(10 * ACCRUAL_PERIOD_NANOS);
+        assert_eq!(overflowing.cash, u128::MAX);
+
+        // As `set_cap` does, settling before the cap changes
+        overflowing.settle(20 * ACCRUAL_PERIOD_NANOS);
+        overflowing.cap = Some(1_000);
+        assert_eq!(overflowing.balance(30 * ACCRUAL_PERIOD_NANOS), u128::MAX);
+
+        let mut capped = user(0, Rate::default());
+        capped.cap = Some(5);
+        assert_eq!(capped.balance(10 * ACCRUAL_PERIOD_NANOS), 5);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::Error;
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+thread_local! {
+    static ADMINS: RefCell<BTreeSet<Principal>> = RefCell::default();
+}
+
+pub fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error {
+            message: "Caller is not a controller".to_string(),
+        })
+    }
+}
+
+// Controllers are always admins, further admins are granted by the controllers
+pub fn ensure_admin() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    let is_admin = ADMINS.with(|admins| admins.borrow().contains(&caller));
+    if is_admin || ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(Error {
+            message: "Caller is not an admin".to_string(),
+        })
+    }
+}
+
+pub fn add(principal: Principal) -> Vec<Principal> {
+    ADMINS.with(|admins| admins.borrow_mut().insert(principal));
+    list()
+}
+
+pub fn remove(principal: &Principal) -> Vec<Principal> {
+    ADMINS.with(|admins| admins.borrow_mut().remove(principal));
+    list()
+}
+
+pub fn list() -> Vec<Principal> {
+    ADMINS.with(|admins| admins.borrow().iter().cloned().collect())
+}
+
+pub fn restore(principals: Vec<Principal>) {
+    ADMINS.with(|admins| *admins.borrow_mut() = principals.into_iter().collect());
+}
@@ -0,0 +1,350 @@
+// Minimal cron support: five UTC fields `minute hour day-of-month month day-of-week`.
+// Each field accepts `*`, values, ranges `a-b`, steps `*/n` or `a-b/n` and comma lists.
+// Months and weekdays also accept three-letter names, and the weekday field accepts
+// `d#n` for the n-th weekday of the month (e.g. `MON#1` for the first Monday).
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+// Searching further than this means the expression can never fire
+const MAX_SEARCH_DAYS: i64 = 400 * 366;
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+pub struct Cron {
+    minutes: u64,                    // Bit n set when minute n matches
+    hours: u64,                      // Bit n set when hour n matches
+    days: u64,                       // Bit n set when day-of-month n matches
+    months: u64,                     // Bit n set when month n matches
+    weekdays: u64,                   // Bit n set when weekday n matches, Sunday being 0
+    nth_weekday: Option<(u32, u32)>, // (weekday, n) for the `d#n` form
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_value(value: &str, names: &[&str], offset: u32) -> Result<u32, String> {
+    if let Ok(number) = value.parse::<u32>() {
+        return Ok(number);
+    }
+    names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(value))
+        .map(|index| index as u32 + offset)
+        .ok_or_else(|| format!("Invalid cron value {:?}", value))
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid cron step {:?}", step))?;
+                (range, Some(step))
+            }
+            None => (item, None),
+        };
+
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (
+                parse_value(start, names, min)?,
+                parse_value(end, names, min)?,
+            ),
+            // `a/n` runs from `a` to the end of the field
+            None if step.is_some() => (parse_value(range, names, min)?, max),
+            None => {
+                let value = parse_value(range, names, min)?;
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end || step == Some(0) {
+            return Err(format!("Cron field {:?} is out of range", item));
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+pub fn parse(expression: &str) -> Result<Cron, String> {
+    let expression = match expression.trim() {
+        "@hourly" => "0 * * * *",
+        "@daily" | "@midnight" => "0 0 * * *",
+        "@weekly" => "0 0 * * 0",
+        "@monthly" => "0 0 1 * *",
+        "@yearly" | "@annually" => "0 0 1 1 *",
+        expression => expression,
+    };
+
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let [minute, hour, day, month, weekday] = fields[..] else {
+        return Err(format!(
+            "Cron expression {:?} must have 5 fields",
+            expression
+        ));
+    };
+
+    let (weekdays, nth_weekday) = match weekday.split_once('#') {
+        Some((weekday, n)) => {
+            let weekday = parse_value(weekday, &WEEKDAYS, 0)?;
+            if weekday > 7 {
+                return Err(format!("Cron weekday {} is out of range", weekday));
+            }
+            let n = n
+                .parse::<u32>()
+                .ok()
+                .filter(|n| (1..=5).contains(n))
+                .ok_or_else(|| format!("Invalid cron weekday occurrence {:?}", n))?;
+            // Both 0 and 7 mean Sunday
+            (0, Some((weekday % 7, n)))
+        }
+        None => {
+            let bits = parse_field(weekday, 0, 7, &WEEKDAYS)?;
+            // Both 0 and 7 mean Sunday
+            ((bits | bits >> 7) & 0x7f, None)
+        }
+    };
+
+    Ok(Cron {
+        minutes: parse_field(minute, 0, 59, &[])?,
+        hours: parse_field(hour, 0, 23, &[])?,
+        days: parse_field(day, 1, 31, &[])?,
+        months: parse_field(month, 1, 12, &MONTHS)?,
+        weekdays,
+        nth_weekday,
+        // As in Vixie cron, a field starting with `*` (such as `*/2`) is unrestricted
+        any_day: day.starts_with('*'),
+        any_weekday: weekday.starts_with('*'),
+    })
+}
+
+// Converts days since 1970-01-01 into a (year, month, day) date in the proleptic
+// Gregorian calendar, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+impl Cron {
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7) as u32;
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = match self.nth_weekday {
+            Some((nth_weekday, n)) => weekday == nth_weekday && (day - 1) / 7 + 1 == n,
+            None => self.weekdays & (1 << weekday) != 0,
+        };
+
+        // As in Vixie cron, a restricted day-of-month and day-of-week match either way
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday_matches,
+            (false, true) => day_matches,
+            (false, false) => day_matches || weekday_matches,
+        }
+    }
+
+    // The first matching time strictly after `time`, both in nanoseconds since the epoch
+    pub fn next_after(&self, time: u64) -> Option<u64> {
+        let start = time / NANOS_PER_MINUTE + 1;
+        let start_day = (start / MINUTES_PER_DAY) as i64;
+        let start_minute = start % MINUTES_PER_DAY;
+
+        for days in start_day..start_day + MAX_SEARCH_DAYS {
+            if !self.matches_day(days) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+                    let minute_of_day = hour * 60 + minute;
+                    if days == start_day && minute_of_day < start_minute {
+                        continue;
+                    }
+                    let minutes = days as u64 * MINUTES_PER_DAY + minute_of_day;
+                    return Some(minutes * NANOS_PER_MINUTE);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Inverse of `civil_from_days`
+    fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let doy = (153 * ((i64::from(month) + 9) % 12) + 2) / 5 + i64::from(day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    // Nanoseconds since the epoch of a UTC date and time
+    fn at(year: i64, month: u32, day: u32, hour: u64, minute: u64) -> u64 {
+        let minutes =
+            days_from_civil(year, month, day) as u64 * MINUTES_PER_DAY + hour * 60 + minute;
+        minutes * NANOS_PER_MINUTE
+    }
+
+    fn next(expression: &str, time: u64) -> Option<u64> {
+        parse(expression).unwrap().next_after(time)
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+        assert_eq!(
+            civil_from_days(days_from_civil(2100, 2, 28) + 1),
+            (2100, 3, 1)
+        );
+        assert_eq!(
+            civil_from_days(days_from_civil(2000, 2, 28) + 1),
+            (2000, 2, 29)
+        );
+    }
+
+    #[test]
+    fn parses_fields() {
+        let cron = parse("0 0 * * *").unwrap();
+        assert_eq!((cron.minutes, cron.hours), (1, 1));
+        assert!(cron.any_day && cron.any_weekday);
+
+        let cron = parse("*/15 9-17 1,15 JAN-MAR MON#1").unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, 0x3fe00);
+        assert_eq!(cron.days, 1 << 1 | 1 << 15);
+        assert_eq!(cron.months, 0b1110);
+        assert_eq!(cron.nth_weekday, Some((1, 1)));
+
+        assert_eq!(parse("0 0 * * 7").unwrap().weekdays, 1);
+        assert_eq!(parse("0 0 * * 7#2").unwrap().nth_weekday, Some((0, 2)));
+        assert!(parse("@daily").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "0 0 * *",
+            "60 0 * * *",
+            "0 24 * * *",
+            "0 0 0 * *",
+            "0 0 * 13 *",
+            "0 0 * * 8",
+            "0 0 * * 9#1",
+            "0 0 * * MON#6",
+            "0 0 5-1 * *",
+            "*/0 * * * *",
+        ] {
+            assert!(
+                parse(expression).is_err(),
+                "{} should be rejected",
+                expression
+            );
+        }
+        assert!(next("0 0 30 2 *", 0).is_none());
+    }
+
+    #[test]
+    fn fires_every_day_at_midnight() {
+        let expression = "0 0 * * *";
+        assert_eq!(
+            next(expression, at(2024, 2, 28, 13, 45)),
+            Some(at(2024, 2, 29, 0, 0))
+        );
+        // Strictly after, so a run at midnight schedules the next day
+        assert_eq!(
+            next(expression, at(2024, 2, 29, 0, 0)),
+            Some(at(2024, 3, 1, 0, 0))
+        );
+        assert_eq!(
+            next(expression, at(2023, 12, 31, 23, 59)),
+            Some(at(2024, 1, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn fires_on_the_first_monday() {
+        let expression = "0 0 * * MON#1";
+        assert_eq!(
+            next(expression, at(2024, 1, 1, 0, 0)),
+            Some(at(2024, 2, 5, 0, 0))
+        );
+        assert_eq!(
+            next(expression, at(2024, 2, 5, 0, 0)),
+            Some(at(2024, 3, 4, 0, 0))
+        );
+        assert_eq!(
+            next(expression, at(2024, 4, 1, 12, 0)),
+            Some(at(2024, 5, 6, 0, 0))
+        );
+    }
+
+    #[test]
+    fn skips_months_without_the_day() {
+        let expression = "0 0 31 * *";
+        assert_eq!(
+            next(expression, at(2024, 1, 31, 0, 0)),
+            Some(at(2024, 3, 31, 0, 0))
+        );
+        assert_eq!(
+            next(expression, at(2024, 3, 31, 0, 0)),
+            Some(at(2024, 5, 31, 0, 0))
+        );
+    }
+
+    #[test]
+    fn fires_on_leap_days() {
+        let expression = "0 0 29 2 *";
+        assert_eq!(
+            next(expression, at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        // 2100 is not a leap year
+        assert_eq!(
+            next(expression, at(2096, 3, 1, 0, 0)),
+            Some(at(2104, 2, 29, 0, 0))
+        );
+    }
+
+    #[test]
+    fn star_steps_leave_the_field_unrestricted() {
+        // With `*/2` unrestricted only the weekday applies, so the odd days in between are skipped
+        let expression = "0 0 */2 * MON";
+        assert_eq!(
+            next(expression, at(2024, 1, 2, 0, 0)),
+            Some(at(2024, 1, 8, 0, 0))
+        );
+        // A restricted day-of-month and day-of-week match either way
+        assert_eq!(
+            next("0 0 1-31/2 * MON", at(2024, 1, 2, 0, 0)),
+            Some(at(2024, 1, 3, 0, 0))
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+
+// Periodic jobs are suspended while the balance is below the reserve
+const DEFAULT_RESERVE: u128 = 1_000_000_000_000;
+
+thread_local! {
+    static RESERVE: RefCell<u128> = const { RefCell::new(DEFAULT_RESERVE) };
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CyclesStatus {
+    pub balance: u128,
+    pub reserve: u128,
+}
+
+pub fn reserve() -> u128 {
+    RESERVE.with(|reserve| *reserve.borrow())
+}
+
+pub fn set_reserve(reserve: u128) -> u128 {
+    RESERVE.with(|reserve_ref| reserve_ref.replace(reserve));
+    reserve
+}
+
+pub fn status() -> CyclesStatus {
+    CyclesStatus {
+        balance: ic_cdk::api::canister_balance128(),
+        reserve: reserve(),
+    }
+}
+
+// Fails with the reason to record when the balance has dropped below the reserve
+pub fn check() -> Result<(), String> {
+    let CyclesStatus { balance, reserve } = status();
+    if balance < reserve {
+        return Err(format!(
+            "Cycles balance {} is below the reserve of {}",
+            balance, reserve
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use crate::Error;
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+pub type HistoryStore = BTreeMap<usize, Series>;
+
+thread_local! {
+    static HISTORY: RefCell<HistoryStore> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy)]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    fn bucket_nanos(self) -> u64 {
+        match self {
+            Resolution::Minute => NANOS_PER_MINUTE,
+            Resolution::Hour => 60 * NANOS_PER_MINUTE,
+            Resolution::Day => 24 * 60 * NANOS_PER_MINUTE,
+        }
+    }
+
+    // Older buckets are dropped: an hour of minutes, a week of hours and a year of days
+    fn retention(self) -> usize {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 7 * 24,
+            Resolution::Day => 365,
+        }
+    }
+}
+
+// The last amount recorded within the bucket starting at `start`
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Bucket {
+    pub start: u64,
+    pub amount: u128,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct Series {
+    minutes: VecDeque<Bucket>,
+    hours: VecDeque<Bucket>,
+    days: VecDeque<Bucket>,
+}
+
+impl Series {
+    fn buckets(&self, resolution: Resolution) -> &VecDeque<Bucket> {
+        match resolution {
+            Resolution::Minute => &self.minutes,
+            Resolution::Hour => &self.hours,
+            Resolution::Day => &self.days,
+        }
+    }
+
+    fn buckets_mut(&mut self, resolution: Resolution) -> &mut VecDeque<Bucket> {
+        match resolution {
+            Resolution::Minute => &mut self.minutes,
+            Resolution::Hour => &mut self.hours,
+            Resolution::Day => &mut self.days,
+        }
+    }
+}
+
+// Records a user's new amount in every resolution. Only changes are recorded, so a
+// missing bucket means the amount stayed the same.
+pub fn record(user_id: usize, amount: u128, now: u64) {
+    HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let series = history.entry(user_id).or_default();
+        for resolution in [Resolution::Minute, Resolution::Hour, Resolution::Day] {
+            let start = now - now % resolution.bucket_nanos();
+            let buckets = series.buckets_mut(resolution);
+            match buckets.back_mut() {
+                Some(last) if last.amount == amount => {}
+                Some(last) if last.start == start => last.amount = amount,
+                _ => {
+                    buckets.push_back(Bucket { start, amount });
+                    if buckets.len() > resolution.retention() {
+                        buckets.pop_front();
+                    }
+                }
+            }
+        }
+    });
+}
+
+// The buckets starting within `from..=to`, preceded by the latest earlier bucket so
+// that a chart knows the amount at `from`
+pub fn range(
+    user_id: usize,
+    from: u64,
+    to: u64,
+    resolution: Resolution,
+) -> Result<Vec<Bucket>, Error> {
+    if from > to {
+        return Err(Error {
+            message: "History range must satisfy from <= to".to_string(),
+        });
+    }
+    HISTORY.with(|history| {
+        let history = history.borrow();
+        let Some(series) = history.get(&user_id) else {
+            return Ok(Vec::new());
+        };
+        let buckets = series.buckets(resolution);
+        let first = buckets.partition_point(|bucket| bucket.start < from);
+        let last = buckets.partition_point(|bucket| bucket.start <= to);
+        Ok(buckets
+            .range(first.saturating_sub(1)..last)
+            .cloned()
+            .collect())
+    })
+}
+
+// The amount recorded last at or before `at`, from the finest resolution that still
+// covers it, so the result may be up to a bucket late
+pub fn amount_at(user_id: usize, at: u64) -> Option<u128> {
+    HISTORY.with(|history| {
+        let history = history.borrow();
+        let series = history.get(&user_id)?;
+        [Resolution::Minute, Resolution::Hour, Resolution::Day]
+            .into_iter()
+            .map(|resolution| series.buckets(resolution))
+            .find(|buckets| buckets.front().is_some_and(|first| first.start <= at))
+            .map(|buckets| {
+                let after = buckets.partition_point(|bucket| bucket.start <= at);
+                buckets[after - 1].amount
+            })
+    })
+}
+
+pub fn save() -> HistoryStore {
+    HISTORY.with(|history| history.borrow().clone())
+}
+
+pub fn restore(store: HistoryStore) {
+    HISTORY.with(|history| *history.borrow_mut() = store);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS_PER_HOUR: u64 = 60 * NANOS_PER_MINUTE;
+
+    fn amounts(buckets: Vec<Bucket>) -> Vec<u128> {
+        buckets.into_iter().map(|bucket| bucket.amount).collect()
+    }
+
+    #[test]
+    fn keeps_the_last_change_per_bucket() {
+        record(1, 10, 0);
+        record(1, 20, 30_000_000_000); // Same minute, overwrites
+        record(1, 20, 2 * NANOS_PER_MINUTE); // Unchanged, not recorded
+        record(1, 30, 3 * NANOS_PER_MINUTE);
+        record(1, 40, 2 * NANOS_PER_HOUR);
+
+        let minutes = range(1, 0, u64::MAX, Resolution::Minute).unwrap();
+        assert_eq!(amounts(minutes), [20, 30, 40]);
+        let hours = range(1, 0, u64::MAX, Resolution::Hour).unwrap();
+        assert_eq!(amounts(hours), [30, 40]);
+
+        // The bucket preceding `from` gives the starting amount
+        let later = range(1, 5 * NANOS_PER_MINUTE, NANOS_PER_HOUR, Resolution::Minute).unwrap();
+        assert_eq!(amounts(later), [30]);
+        assert!(range(1, 1, 0, Resolution::Minute).is_err());
+        assert!(range(2, 0, u64::MAX, Resolution::Day).unwrap().is_empty());
+
+        assert_eq!(amount_at(1, NANOS_PER_HOUR), Some(30));
+        assert_eq!(amount_at(2, NANOS_PER_HOUR), None);
+    }
+
+    #[test]
+    fn drops_buckets_past_the_retention() {
+        let retention = Resolution::Minute.retention() as u64;
+        for minute in 0..retention + 5 {
+            record(1, minute as u128, minute * NANOS_PER_MINUTE);
+        }
+        let minutes = range(1, 0, u64::MAX, Resolution::Minute).unwrap();
+        assert_eq!(minutes.len() as u64, retention);
+        assert_eq!(minutes[0].amount, 5);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::Error;
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use std::cell::RefCell;
+
+// Only the most recent changes are kept in the audit log
+const MAX_CHANGES: usize = 100;
+
+pub type IntervalState = (IntervalBounds, Vec<IntervalChange>);
+
+thread_local! {
+    static BOUNDS: RefCell<IntervalBounds> = RefCell::default();
+    static CHANGES: RefCell<Vec<IntervalChange>> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct IntervalBounds {
+    pub min_seconds: u64,
+    pub max_seconds: u64,
+}
+
+impl Default for IntervalBounds {
+    fn default() -> Self {
+        IntervalBounds {
+            min_seconds: 1,
+            max_seconds: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct IntervalChange {
+    pub caller: Principal,
+    pub previous_seconds: Option<u64>,
+    pub seconds: u64,
+    pub changed_at: u64,
+}
+
+pub fn bounds() -> IntervalBounds {
+    BOUNDS.with(|bounds| bounds.borrow().clone())
+}
+
+pub fn set_bounds(bounds: IntervalBounds) -> Result<IntervalBounds, Error> {
+    if bounds.min_seconds == 0 || bounds.min_seconds > bounds.max_seconds {
+        return Err(Error {
+            message: "Interval bounds must satisfy 0 < min_seconds <= max_seconds".to_string(),
+        });
+    }
+    BOUNDS.with(|bounds_ref| bounds_ref.replace(bounds.clone()));
+    Ok(bounds)
+}
+
+pub fn validate(seconds: u64) -> Result<(), Error> {
+    let bounds = bounds();
+    if seconds < bounds.min_seconds || seconds > bounds.max_seconds {
+        return Err(Error {
+            message: format!(
+                "Interval must be between {} and {} seconds",
+                bounds.min_seconds, bounds.max_seconds
+            ),
+        });
+    }
+    Ok(())
+}
+
+pub fn record(previous_seconds: Option<u64>, seconds: u64) {
+    let change = IntervalChange {
+        caller: ic_cdk::caller(),
+        previous_seconds,
+        seconds,
+        changed_at: ic_cdk::api::time(),
+    };
+    CHANGES.with(|changes| {
+        let mut changes = changes.borrow_mut();
+        if changes.len() == MAX_CHANGES {
+            changes.remove(0);
+        }
+        changes.push(change);
+    });
+}
+
+pub fn changes() -> Vec<IntervalChange> {
+    CHANGES.with(|changes| changes.borrow().clone())
+}
+
+pub fn save() -> IntervalState {
+    (bounds(), changes())
+}
+
+pub fn restore((bounds, changes): IntervalState) {
+    BOUNDS.with(|bounds_ref| bounds_ref.replace(bounds));
+    CHANGES.with(|changes_ref| changes_ref.replace(changes));
+}
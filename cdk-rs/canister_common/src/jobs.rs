@@ -0,0 +1,543 @@
+use crate::{cron, cycles, interval, Error};
+use candid::{CandidType, Deserialize};
+use ic_cdk_timers::TimerId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub type JobStore<T> = BTreeMap<String, Job<T>>;
+
+// How often suspended jobs check whether the canister has been topped up
+const CYCLES_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Upper bound on the missed ticks replayed by a single run
+pub const MAX_CATCH_UP_TICKS: u64 = 1_000;
+
+thread_local! {
+    // The canister's `JobStore<T>`, created on first use
+    static JOBS: RefCell<Option<Box<dyn Any>>> = RefCell::default();
+    // Timers do not survive upgrades, so they are kept apart from the persisted jobs
+    static TIMERS: RefCell<BTreeMap<String, TimerId>> = RefCell::default();
+    static CYCLES_CHECK: RefCell<Option<TimerId>> = RefCell::default();
+}
+
+// The work a job runs, defined by each canister. A canister schedules a single task type.
+pub trait Task: CandidType + DeserializeOwned + Clone + 'static {
+    // `ticks` is more than 1 when a job catches up on missed ticks
+    fn run(&self, job: &str, ticks: u64);
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum Schedule {
+    Interval { seconds: u64 }, // Runs every `seconds` until paused or cancelled
+    Once { at: u64 },          // Runs a single time, `at` nanoseconds since the epoch
+    Cron { expression: String }, // Runs whenever the UTC cron expression matches, see `cron.rs`
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum JobStatus {
+    Active,
+    Paused,
+    Completed,
+    Suspended { reason: String, since: u64 }, // Paused by the cycles guard until topped up
+}
+
+// What an interval job does about ticks lost while the canister was stopped,
+// upgraded or stalled
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum MissedTickPolicy {
+    Skip,    // Resume the schedule from now on
+    CatchUp, // Run the task once more for every missed tick
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Job<T> {
+    pub name: String,
+    pub task: T,
+    pub schedule: Schedule,
+    pub status: JobStatus,
+    pub created_at: u64,
+    pub last_run_at: Option<u64>,
+    pub next_run_at: Option<u64>,
+    pub run_count: u64,
+    pub last_error: Option<JobError>,
+    pub missed_tick_policy: MissedTickPolicy,
+    pub missed_ticks: u64, // Ticks missed since the job was created
+    pub drift_nanos: u64,  // How late the last run fired compared to its expected time
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct JobError {
+    pub message: String,
+    pub at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct TimerStatus {
+    pub status: JobStatus,
+    pub interval_seconds: Option<u64>,
+    pub last_run_at: Option<u64>,
+    pub next_run_at: Option<u64>,
+    pub run_count: u64,
+    pub last_error: Option<JobError>,
+    pub missed_tick_policy: MissedTickPolicy,
+    pub missed_ticks: u64,
+    pub drift_nanos: u64,
+}
+
+fn with_jobs<T: Task, R>(f: impl FnOnce(&mut JobStore<T>) -> R) -> R {
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let jobs = jobs.get_or_insert_with(|| Box::new(JobStore::<T>::new()));
+        f(jobs
+            .downcast_mut()
+            .expect("Jobs must all run the same task type"))
+    })
+}
+
+fn not_found(name: &str) -> Error {
+    Error {
+        message: format!("Job {} not found", name),
+    }
+}
+
+// The next time a cron expression fires after `now`
+fn next_cron_run(expression: &str, now: u64) -> Result<u64, Error> {
+    let cron = cron::parse(expression).map_err(|message| Error { message })?;
+    cron.next_after(now).ok_or_else(|| Error {
+        message: format!("Cron expression {:?} never fires", expression),
+    })
+}
+
+fn validate(schedule: &Schedule) -> Result<(), Error> {
+    match schedule {
+        Schedule::Interval { seconds } => {
+            interval::validate(*seconds)?;
+            match seconds.checked_mul(NANOS_PER_SECOND) {
+                Some(_) => Ok(()),
+                None => Err(Error {
+                    message: format!("Interval of {} seconds is too long", seconds),
+                }),
+            }
+        }
+        Schedule::Cron { expression } => next_cron_run(expression, ic_cdk::api::time()).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+// When an active job runs once it is armed at `now`. An interval job keeps the
+// run it was waiting for, so that its schedule survives upgrades; one that fell
+// due in the meantime runs right away. A cron job runs at the next match after
+// `now`, skipping the ones it missed.
+pub fn due_at<T>(job: &Job<T>, now: u64) -> Result<u64, Error> {
+    match &job.schedule {
+        Schedule::Interval { seconds } => Ok(job
+            .next_run_at
+            .unwrap_or_else(|| now.saturating_add(seconds.saturating_mul(NANOS_PER_SECOND)))
+            .max(now)),
+        Schedule::Once { at } => Ok(now.max(*at)),
+        Schedule::Cron { expression } => next_cron_run(expression, now),
+    }
+}
+
+// How late a run fired after the time it was `expected` at, and how many full
+// periods of an interval job were missed in between
+fn drift(expected: Option<u64>, now: u64, period: u64) -> (u64, u64) {
+    let drift_nanos = now.saturating_sub(expected.unwrap_or(now));
+    (drift_nanos, drift_nanos / period)
+}
+
+fn set_next_run_at<T: Task>(name: &str, next_run_at: Option<u64>) {
+    with_jobs(|jobs: &mut JobStore<T>| {
+        if let Some(job) = jobs.get_mut(name) {
+            job.next_run_at = next_run_at;
+        }
+    });
+}
+
+fn clear_timer(name: &str) {
+    if let Some(timer_id) = TIMERS.with(|timers| timers.borrow_mut().remove(name)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+}
+
+fn disarm<T: Task>(name: &str) {
+    clear_timer(name);
+    set_next_run_at::<T>(name, None);
+}
+
+// Interval and cron jobs are re-armed with a one-shot timer after every run, so
+// that the time of their next run is known
+fn arm<T: Task>(job: &Job<T>) {
+    clear_timer(&job.name);
+
+    let now = ic_cdk::api::time();
+    let next_run_at = match due_at(job, now) {
+        Ok(next_run_at) => next_run_at,
+        Err(e) => {
+            ic_cdk::println!("Failed to schedule job {:?}: {}", job.name, e.message);
+            set_next_run_at::<T>(&job.name, None);
+            return;
+        }
+    };
+    let delay = Duration::from_nanos(next_run_at - now);
+    let name = job.name.clone();
+    ic_cdk::println!("Scheduling job {:?} to run in {:?}", name, delay);
+    let timer_id = ic_cdk_timers::set_timer(delay, move || run::<T>(&name));
+    TIMERS.with(|timers| timers.borrow_mut().insert(job.name.clone(), timer_id));
+
+    // An overdue interval run fires right away but keeps the time it was due at,
+    // so that the run can tell how many ticks were missed
+    let expected = match job.schedule {
+        Schedule::Interval { .. } => job.next_run_at.unwrap_or(next_run_at),
+        _ => next_run_at,
+    };
+    set_next_run_at::<T>(&job.name, Some(expected));
+}
+
+fn run<T: Task>(name: &str) {
+    // One-shot timers are dropped by the runtime once they fire
+    TIMERS.with(|timers| timers.borrow_mut().remove(name));
+
+    if let Err(reason) = cycles::check() {
+        suspend::<T>(name, reason);
+        return;
+    }
+
+    let job = with_jobs(|jobs: &mut JobStore<T>| {
+        let job = jobs.get_mut(name)?;
+
+        let now = ic_cdk::api::time();
+        let mut ticks = 1;
+        job.run_count += 1;
+        job.last_run_at = Some(now);
+        match job.schedule {
+            Schedule::Interval { seconds } => {
+                let period = seconds.saturating_mul(NANOS_PER_SECOND);
+                let (drift_nanos, missed) = drift(job.next_run_at, now, period);
+                job.drift_nanos = drift_nanos;
+                job.missed_ticks = job.missed_ticks.saturating_add(missed);
+                if missed > 0 {
+                    ic_cdk::println!("Job {:?} missed {} ticks", name, missed);
+                }
+                if job.missed_tick_policy == MissedTickPolicy::CatchUp {
+                    ticks += missed.min(MAX_CATCH_UP_TICKS);
+                }
+            }
+            Schedule::Once { .. } => job.status = JobStatus::Completed,
+            Schedule::Cron { .. } => {}
+        }
+        job.next_run_at = None;
+        Some((job.clone(), ticks))
+    });
+    let Some((job, ticks)) = job else { return };
+
+    if job.status == JobStatus::Active {
+        arm(&job);
+    }
+
+    // The registry is released before running so that tasks may inspect or schedule jobs
+    job.task.run(&job.name, ticks);
+}
+
+fn suspend<T: Task>(name: &str, reason: String) {
+    ic_cdk::println!("Suspending job {:?}: {}", name, reason);
+    with_jobs(|jobs: &mut JobStore<T>| {
+        if let Some(job) = jobs.get_mut(name) {
+            job.status = JobStatus::Suspended {
+                reason,
+                since: ic_cdk::api::time(),
+            };
+        }
+    });
+    disarm::<T>(name);
+    watch_cycles::<T>();
+}
+
+// A single timer watches the balance on behalf of all suspended jobs
+fn watch_cycles<T: Task>() {
+    if CYCLES_CHECK.with(|check| check.borrow().is_some()) {
+        return;
+    }
+    let timer_id = ic_cdk_timers::set_timer_interval(CYCLES_CHECK_INTERVAL, resume_suspended::<T>);
+    CYCLES_CHECK.with(|check| check.replace(Some(timer_id)));
+}
+
+// Re-arms the suspended jobs once the balance is back above the reserve
+pub fn resume_suspended<T: Task>() {
+    if cycles::check().is_err() {
+        return;
+    }
+    if let Some(timer_id) = CYCLES_CHECK.with(|check| check.take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+
+    let suspended = with_jobs(|jobs: &mut JobStore<T>| {
+        let mut suspended = Vec::new();
+        for job in jobs.values_mut() {
+            if let JobStatus::Suspended { .. } = job.status {
+                job.status = JobStatus::Active;
+                suspended.push(job.clone());
+            }
+        }
+        suspended
+    });
+    for job in suspended {
+        ic_cdk::println!("Resuming job {:?} after a top-up", job.name);
+        arm(&job);
+    }
+}
+
+// Adds a new job and arms its timer
+pub fn register<T: Task>(name: String, task: T, schedule: Schedule) -> Result<Job<T>, Error> {
+    if get::<T>(&name).is_some() {
+        return Err(Error {
+            message: format!("Job {} already exists", name),
+        });
+    }
+    set(name, task, schedule)
+}
+
+// Creates or replaces a job, keeping the bookkeeping of a replaced one
+pub fn set<T: Task>(name: String, task: T, schedule: Schedule) -> Result<Job<T>, Error> {
+    validate(&schedule)?;
+
+    let job = with_jobs(|jobs: &mut JobStore<T>| {
+        let job = jobs.entry(name.clone()).or_insert_with(|| Job {
+            name,
+            task: task.clone(),
+            schedule: schedule.clone(),
+            status: JobStatus::Active,
+            created_at: ic_cdk::api::time(),
+            last_run_at: None,
+            next_run_at: None,
+            run_count: 0,
+            last_error: None,
+            missed_tick_policy: MissedTickPolicy::Skip,
+            missed_ticks: 0,
+            drift_nanos: 0,
+        });
+        job.task = task;
+        job.schedule = schedule;
+        job.status = JobStatus::Active;
+        job.next_run_at = None; // The new schedule starts from now
+        job.clone()
+    });
+    arm(&job);
+
+    Ok(get(&job.name).unwrap_or(job))
+}
+
+pub fn get<T: Task>(name: &str) -> Option<Job<T>> {
+    with_jobs(|jobs: &mut JobStore<T>| jobs.get(name).cloned())
+}
+
+pub fn list<T: Task>() -> Vec<Job<T>> {
+    with_jobs(|jobs: &mut JobStore<T>| jobs.values().cloned().collect())
+}
+
+pub fn status<T: Task>(name: &str) -> Result<TimerStatus, Error> {
+    let job = get::<T>(name).ok_or_else(|| not_found(name))?;
+    let interval_seconds = match job.schedule {
+        Schedule::Interval { seconds } => Some(seconds),
+        _ => None,
+    };
+    Ok(TimerStatus {
+        status: job.status,
+        interval_seconds,
+        last_run_at: job.last_run_at,
+        next_run_at: job.next_run_at,
+        run_count: job.run_count,
+        last_error: job.last_error,
+        missed_tick_policy: job.missed_tick_policy,
+        missed_ticks: job.missed_ticks,
+        drift_nanos: job.drift_nanos,
+    })
+}
+
+pub fn set_missed_tick_policy<T: Task>(
+    name: &str,
+    policy: MissedTickPolicy,
+) -> Result<Job<T>, Error> {
+    with_jobs(|jobs: &mut JobStore<T>| {
+        let job = jobs.get_mut(name).ok_or_else(|| not_found(name))?;
+        job.missed_tick_policy = policy;
+        Ok(job.clone())
+    })
+}
+
+// Records a failure of the job's task, which may happen after the task was spawned
+pub fn report_error<T: Task>(name: &str, message: String) {
+    ic_cdk::println!("Job {:?} failed: {}", name, message);
+    with_jobs(|jobs: &mut JobStore<T>| {
+        if let Some(job) = jobs.get_mut(name) {
+            job.last_error = Some(JobError {
+                message,
+                at: ic_cdk::api::time(),
+            });
+        }
+    });
+}
+
+pub fn pause<T: Task>(name: &str) -> Result<Job<T>, Error> {
+    with_jobs(|jobs: &mut JobStore<T>| {
+        let job = jobs.get_mut(name).ok_or_else(|| not_found(name))?;
+        // A job suspended by the cycles guard may still be paused by hand
+        if let JobStatus::Paused | JobStatus::Completed = job.status {
+            return Err(Error {
+                message: format!("Job {} is not active", name),
+            });
+        }
+        job.status = JobStatus::Paused;
+        Ok(())
+    })?;
+    disarm::<T>(name);
+
+    get(name).ok_or_else(|| not_found(name))
+}
+
+pub fn resume<T: Task>(name: &str) -> Result<Job<T>, Error> {
+    let job = with_jobs(|jobs: &mut JobStore<T>| {
+        let job = jobs.get_mut(name).ok_or_else(|| not_found(name))?;
+        if job.status != JobStatus::Paused {
+            return Err(Error {
+                message: format!("Job {} is not paused", name),
+            });
+        }
+        job.status = JobStatus::Active;
+        Ok(job.clone())
+    })?;
+    arm(&job);
+
+    get(name).ok_or_else(|| not_found(name))
+}
+
+pub fn cancel<T: Task>(name: &str) -> Result<Job<T>, Error> {
+    let job = with_jobs(|jobs: &mut JobStore<T>| jobs.remove(name).ok_or_else(|| not_found(name)))?;
+    clear_timer(name);
+
+    Ok(job)
+}
+
+pub fn save<T: Task>() -> JobStore<T> {
+    with_jobs(|jobs: &mut JobStore<T>| jobs.clone())
+}
+
+// Restores the persisted jobs, re-arms the active ones (see `due_at`) and
+// watches the balance for the suspended ones
+pub fn restore<T: Task>(store: JobStore<T>) {
+    with_jobs(|jobs: &mut JobStore<T>| *jobs = store);
+    for job in list::<T>() {
+        match job.status {
+            JobStatus::Active => arm(&job),
+            JobStatus::Suspended { .. } => watch_cycles::<T>(),
+            JobStatus::Paused | JobStatus::Completed => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::IntervalBounds;
+
+    fn job(schedule: Schedule, next_run_at: Option<u64>) -> Job<()> {
+        Job {
+            name: "job".to_string(),
+            task: (),
+            schedule,
+            status: JobStatus::Active,
+            created_at: 0,
+            last_run_at: None,
+            next_run_at,
+            run_count: 0,
+            last_error: None,
+            missed_tick_policy: MissedTickPolicy::CatchUp,
+            missed_ticks: 0,
+            drift_nanos: 0,
+        }
+    }
+
+    #[test]
+    fn keeps_the_schedule_of_a_pending_run() {
+        let every_minute = Schedule::Interval { seconds: 60 };
+        assert_eq!(
+            due_at(&job(every_minute.clone(), Some(100)), 40).unwrap(),
+            100
+        );
+        assert_eq!(
+            due_at(&job(every_minute.clone(), Some(100)), 140).unwrap(),
+            140
+        );
+        assert_eq!(
+            due_at(&job(every_minute, None), 40).unwrap(),
+            40 + 60 * NANOS_PER_SECOND
+        );
+        let once = job(Schedule::Once { at: 50 }, None);
+        assert_eq!(due_at(&once, 100).unwrap(), 100);
+    }
+
+    #[test]
+    fn counts_the_ticks_a_late_run_missed() {
+        let minute = 60 * NANOS_PER_SECOND;
+        assert_eq!(drift(Some(100), 100, minute), (0, 0));
+        assert_eq!(drift(Some(100), 100 + minute - 1, minute), (minute - 1, 0));
+        assert_eq!(
+            drift(Some(100), 100 + 5 * minute / 2, minute),
+            (5 * minute / 2, 2)
+        );
+        // A run without an expected time, or one that fired early, is not late
+        assert_eq!(drift(None, 100, minute), (0, 0));
+        assert_eq!(drift(Some(200), 100, minute), (0, 0));
+    }
+
+    #[test]
+    fn runs_cron_jobs_at_the_next_match() {
+        let hourly = job(
+            Schedule::Cron {
+                expression: "0 * * * *".to_string(),
+            },
+            Some(0),
+        );
+        let hour = 60 * 60 * NANOS_PER_SECOND;
+        assert_eq!(due_at(&hourly, 0).unwrap(), hour);
+        assert_eq!(due_at(&hourly, hour + 1).unwrap(), 2 * hour);
+
+        let never = Schedule::Cron {
+            expression: "0 0 30 2 *".to_string(),
+        };
+        assert!(due_at(&job(never, None), 0).is_err());
+    }
+
+    #[test]
+    fn intervals_must_lie_within_the_bounds() {
+        assert!(validate(&Schedule::Interval { seconds: 0 }).is_err());
+        assert!(validate(&Schedule::Interval { seconds: 60 }).is_ok());
+        assert!(validate(&Schedule::Interval {
+            seconds: 24 * 60 * 60 + 1
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn intervals_must_fit_in_nanoseconds() {
+        interval::set_bounds(IntervalBounds {
+            min_seconds: 1,
+            max_seconds: u64::MAX,
+        })
+        .unwrap();
+        assert!(validate(&Schedule::Interval {
+            seconds: u64::MAX / NANOS_PER_SECOND
+        })
+        .is_ok());
+        assert!(validate(&Schedule::Interval {
+            seconds: u64::MAX / NANOS_PER_SECOND + 1
+        })
+        .is_err());
+    }
+}
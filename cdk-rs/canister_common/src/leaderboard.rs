@@ -0,0 +1,66 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+// Longest ranking a single query returns
+pub const MAX_LIMIT: usize = 100;
+
+thread_local! {
+    // User ids by descending amount, ties going to the lower id. Kept up to date on
+    // every change so that queries only walk the top of the set.
+    static INDEX: RefCell<BTreeSet<(Reverse<u128>, usize)>> = RefCell::default();
+}
+
+// Moves a user from `previous` to `amount`, `previous` being None for a new user
+pub fn update(id: usize, previous: Option<u128>, amount: u128) {
+    if previous == Some(amount) {
+        return;
+    }
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&(Reverse(previous), id));
+        }
+        index.insert((Reverse(amount), id));
+    });
+}
+
+// Ids of the `limit` highest ranked users, capped at `MAX_LIMIT`
+pub fn top(limit: usize) -> Vec<usize> {
+    INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .take(limit.min(MAX_LIMIT))
+            .map(|(_, id)| *id)
+            .collect()
+    })
+}
+
+// The index is derived from the users, so it is rebuilt rather than persisted
+pub fn rebuild(entries: impl IntoIterator<Item = (usize, u128)>) {
+    let index = entries
+        .into_iter()
+        .map(|(id, amount)| (Reverse(amount), id))
+        .collect();
+    INDEX.with(|index_ref| index_ref.replace(index));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_the_highest_amounts_first() {
+        rebuild([(1, 50), (2, 80), (3, 50)]);
+        assert_eq!(top(10), [2, 1, 3]);
+
+        update(3, Some(50), 90);
+        update(4, None, 60);
+        assert_eq!(top(2), [3, 2]);
+        assert_eq!(top(10), [3, 2, 4, 1]);
+
+        rebuild((0..2 * MAX_LIMIT).map(|id| (id, id as u128)));
+        assert_eq!(top(usize::MAX).len(), MAX_LIMIT);
+    }
+}
@@ -0,0 +1,17 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+pub mod admin;
+pub mod cash;
+pub mod cron;
+pub mod cycles;
+pub mod history;
+pub mod interval;
+pub mod jobs;
+pub mod leaderboard;
+pub mod transfers;
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct Error {
+    pub message: String,
+}
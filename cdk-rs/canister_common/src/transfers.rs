@@ -0,0 +1,256 @@
+use crate::Error;
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Transfers carrying `created_at_time` are deduplicated within this window
+const TRANSACTION_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+// Longest page a single query returns
+pub const MAX_LIMIT: usize = 100;
+
+thread_local! {
+    static TRANSACTIONS: RefCell<Vec<Transaction>> = RefCell::default();
+    // IDs of the transactions each user sent or received
+    static BY_USER: RefCell<BTreeMap<usize, BTreeSet<u64>>> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Transaction {
+    pub id: u64,
+    pub from_id: usize,
+    pub to_id: usize,
+    pub amount: u128,
+    pub memo: Option<String>,
+    pub created_at_time: Option<u64>, // Set by the caller to deduplicate retries
+    pub timestamp: u64,
+}
+
+#[derive(Serialize)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    pub next_cursor: Option<u64>, // Pass as `cursor` to read the following, older page
+}
+
+// Rejects a transfer whose `created_at_time` is outside the deduplication window
+// or that repeats a transfer already recorded within it
+fn check_duplicate(transaction: &Transaction, now: u64) -> Result<(), Error> {
+    let created_at_time = match transaction.created_at_time {
+        Some(created_at_time) => created_at_time,
+        None => return Ok(()),
+    };
+
+    let window = TRANSACTION_WINDOW_NANOS + PERMITTED_DRIFT_NANOS;
+    if created_at_time.saturating_add(window) < now {
+        return Err(Error {
+            message: "Transaction is too old".to_string(),
+        });
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err(Error {
+            message: "Transaction is created in the future".to_string(),
+        });
+    }
+
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+
+        // Only transactions recorded within the window can be duplicates
+        let duplicate = transactions
+            .iter()
+            .rev()
+            .take_while(|tx| tx.timestamp.saturating_add(window) >= now)
+            .find(|tx| {
+                tx.created_at_time == transaction.created_at_time
+                    && tx.from_id == transaction.from_id
+                    && tx.to_id == transaction.to_id
+                    && tx.amount == transaction.amount
+                    && tx.memo == transaction.memo
+            });
+
+        match duplicate {
+            Some(tx) => Err(Error {
+                message: format!("Duplicate of transaction {}", tx.id),
+            }),
+            None => Ok(()),
+        }
+    })
+}
+
+// The transaction of a transfer requested at `now`, once it is checked to move a
+// positive amount between two users and not to repeat an earlier transfer
+pub fn prepare(
+    from_id: usize,
+    to_id: usize,
+    amount: u128,
+    memo: Option<String>,
+    created_at_time: Option<u64>,
+    now: u64,
+) -> Result<Transaction, Error> {
+    if from_id == to_id {
+        return Err(Error {
+            message: "Cannot transfer cash to the same user".to_string(),
+        });
+    }
+    if amount == 0 {
+        return Err(Error {
+            message: "Transfer amount must be greater than 0".to_string(),
+        });
+    }
+
+    let transaction = Transaction {
+        id: 0,
+        from_id,
+        to_id,
+        amount,
+        memo,
+        created_at_time,
+        timestamp: now,
+    };
+    check_duplicate(&transaction, now)?;
+    Ok(transaction)
+}
+
+// The sender's and the recipient's cash once the transaction is applied. Both are
+// checked before the caller writes either, so that the transfer is all-or-nothing.
+pub fn apply(
+    transaction: &Transaction,
+    from_cash: u128,
+    to_cash: u128,
+) -> Result<(u128, u128), Error> {
+    let from_cash = from_cash
+        .checked_sub(transaction.amount)
+        .ok_or_else(|| Error {
+            message: format!(
+                "Insufficient cash: user {} holds {}",
+                transaction.from_id, from_cash
+            ),
+        })?;
+    let to_cash = to_cash
+        .checked_add(transaction.amount)
+        .ok_or_else(|| Error {
+            message: format!("Cash of user {} would overflow", transaction.to_id),
+        })?;
+    Ok((from_cash, to_cash))
+}
+
+fn index(transaction: &Transaction) {
+    BY_USER.with(|by_user| {
+        let mut by_user = by_user.borrow_mut();
+        for user_id in [transaction.from_id, transaction.to_id] {
+            by_user.entry(user_id).or_default().insert(transaction.id);
+        }
+    });
+}
+
+// Appends the transaction to the log, returning its ID
+pub fn record(transaction: Transaction) -> u64 {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+
+        let id = transactions.len() as u64;
+        let transaction = Transaction { id, ..transaction };
+        index(&transaction);
+        transactions.push(transaction);
+        id
+    })
+}
+
+// The transfers the user sent or received, newest first, starting below transaction
+// `cursor` if given
+pub fn page(user_id: usize, cursor: Option<u64>, limit: usize) -> TransactionPage {
+    let limit = limit.min(MAX_LIMIT);
+    let mut ids: Vec<u64> = BY_USER.with(|by_user| {
+        by_user
+            .borrow()
+            .get(&user_id)
+            .map(|ids| {
+                ids.range(..cursor.unwrap_or(u64::MAX))
+                    .rev()
+                    .take(limit + 1)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    let more = ids.len() > limit;
+    ids.truncate(limit);
+
+    TransactionPage {
+        transactions: TRANSACTIONS.with(|transactions| {
+            let transactions = transactions.borrow();
+            ids.iter()
+                .map(|id| transactions[*id as usize].clone())
+                .collect()
+        }),
+        next_cursor: if more { ids.last().copied() } else { None },
+    }
+}
+
+pub fn save() -> Vec<Transaction> {
+    TRANSACTIONS.with(|transactions| transactions.borrow().clone())
+}
+
+// Restores the saved log and rebuilds the per-user index from it
+pub fn restore(transactions: Vec<Transaction>) {
+    BY_USER.with(|by_user| by_user.borrow_mut().clear());
+    transactions.iter().for_each(index);
+    TRANSACTIONS.with(|transactions_ref| *transactions_ref.borrow_mut() = transactions);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 10 * TRANSACTION_WINDOW_NANOS;
+
+    #[test]
+    fn rejects_duplicates_within_the_window() {
+        let transaction = prepare(1, 2, 5, None, Some(NOW), NOW).unwrap();
+        let id = record(transaction.clone());
+        let retry = prepare(1, 2, 5, None, Some(NOW), NOW + 1);
+        assert_eq!(
+            retry.err().unwrap().message,
+            format!("Duplicate of transaction {}", id)
+        );
+        assert!(prepare(1, 2, 6, None, Some(NOW), NOW + 1).is_ok());
+        assert!(prepare(1, 2, 5, None, None, NOW + 1).is_ok());
+
+        let later = NOW + TRANSACTION_WINDOW_NANOS;
+        assert!(prepare(1, 2, 5, None, Some(later), later).is_ok());
+    }
+
+    #[test]
+    fn rejects_times_outside_the_window() {
+        assert!(prepare(1, 2, 5, None, Some(0), NOW).is_err());
+        assert!(prepare(1, 2, 5, None, Some(u64::MAX), NOW).is_err());
+        // Near the end of time the window saturates rather than overflows
+        assert!(prepare(1, 2, 5, None, Some(u64::MAX), u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn pages_a_users_transactions() {
+        for to_id in 2..=6 {
+            record(prepare(1, to_id, 5, None, None, NOW).unwrap());
+        }
+        record(prepare(3, 2, 5, None, None, NOW).unwrap());
+
+        let first = page(2, None, 1);
+        assert_eq!(first.transactions[0].id, 5);
+        assert_eq!(first.next_cursor, Some(5));
+        let second = page(2, first.next_cursor, 10);
+        assert_eq!(second.transactions[0].id, 0);
+        assert_eq!(second.next_cursor, None);
+
+        restore(save());
+        let ids: Vec<u64> = page(1, None, 10)
+            .transactions
+            .iter()
+            .map(|tx| tx.id)
+            .collect();
+        assert_eq!(ids, vec![4, 3, 2, 1, 0]);
+        assert!(page(7, None, 10).transactions.is_empty());
+    }
+}
@@ -1,20 +1,27 @@
-use candid::{CandidType, Deserialize};
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::cycles::{self, CyclesStatus};
+use canister_common::interval::{self, IntervalBounds, IntervalChange, IntervalState};
+use canister_common::jobs::{self, Job, JobStore, MissedTickPolicy, Schedule, TimerStatus};
+use canister_common::{admin, Error};
 use ic_cdk::api::management_canister::http_request::{
     http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
     TransformContext, TransformFunc,
 };
 use ic_cdk_macros::*;
 use serde::Serialize;
-use std::cell::RefCell;
 
-thread_local! {
-    static INTERVAL_IN_SECONDS: RefCell<u64> = RefCell::default();
-    static TIMERS: RefCell<ic_cdk_timers::TimerId> = RefCell::default();
-}
+// Version of the state saved by `pre_upgrade`, bumped when a field changes meaning.
+// Fields are optional, so that state saved before a field was added still decodes.
+const STATE_VERSION: u32 = 1;
 
-#[derive(CandidType, Deserialize, Serialize)]
-struct Error {
-    message: String,
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+    version: u32,
+    jobs: Option<JobStore<Task>>,
+    admins: Option<Vec<Principal>>,
+    interval_settings: Option<IntervalState>,
+    cycles_reserve: Option<u128>,
 }
 
 fn count_instructions(start_counter: u64, function_name: String) {
@@ -33,7 +40,7 @@ fn call_context_count_instructions(start_counter: u64, function_name: String) {
     );
 }
 
-async fn call_http_outcall() {
+async fn call_http_outcall(job: String) {
     let call_start_instructions = ic_cdk::api::call_context_instruction_counter();
     let url = "https://api.kanye.rest/";
     let request_headers = vec![
@@ -64,7 +71,11 @@ async fn call_http_outcall() {
 
     let cycles = 1_604_000_000; // Adjust based on your requirements
 
-    let _ = match http_request(request, cycles).await {
+    match http_request(request, cycles).await {
+        Ok((response,)) if response.status != 200u64 => {
+            let msg = format!("Quote API responded with status {}", response.status);
+            jobs::report_error::<Task>(&job, msg);
+        }
         Ok((response,)) => {
             let msg = String::from_utf8(response.body)
                 .unwrap_or_else(|_| "Failed to decode response".to_string());
@@ -72,7 +83,7 @@ async fn call_http_outcall() {
         }
         Err((_, message)) => {
             let msg = format!("Failed to fetch quote: {}", message);
-            ic_cdk::println!("Response: {}", msg);
+            jobs::report_error::<Task>(&job, msg);
         }
     };
 
@@ -100,65 +111,369 @@ fn transform_quote(raw: TransformArgs) -> HttpResponse {
     res
 }
 
+const DEFAULT_JOB: &str = "fetch_quote";
+const DEFAULT_INTERVAL_IN_SECONDS: u64 = 15;
+
+// Work that can be scheduled through the job registry
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+enum Task {
+    FetchQuote,
+}
+
+impl jobs::Task for Task {
+    fn run(&self, job: &str, _ticks: u64) {
+        match self {
+            Task::FetchQuote => ic_cdk::spawn(call_http_outcall(job.to_string())),
+        }
+    }
+}
+
 #[ic_cdk::init]
-async fn init() {
+fn init() {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    let seconds = 15;
-    INTERVAL_IN_SECONDS.with(|interval_ref| {
-        interval_ref.replace(seconds);
-    });
+    let schedule = Schedule::Interval {
+        seconds: DEFAULT_INTERVAL_IN_SECONDS,
+    };
+    jobs::set(DEFAULT_JOB.to_string(), Task::FetchQuote, schedule).unwrap();
 
-    let interval = std::time::Duration::from_secs(seconds);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
-        ic_cdk::spawn(call_http_outcall());
-    });
+    count_instructions(start_instructions, "init".to_string());
+}
 
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(timer_id);
-    });
+fn save_state() -> StableState {
+    StableState {
+        version: STATE_VERSION,
+        jobs: Some(jobs::save()),
+        admins: Some(admin::list()),
+        interval_settings: Some(interval::save()),
+        cycles_reserve: Some(cycles::reserve()),
+    }
+}
 
-    count_instructions(start_instructions, "init".to_string());
+// The state saved by `pre_upgrade`. Stable memory is grown in whole pages, so the
+// encoded state is followed by zeros.
+fn decode_state(bytes: &[u8]) -> candid::Result<StableState> {
+    IDLDeserialize::new(bytes)?.get_value()
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((save_state(),)).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // The previous version saved nothing, so there is nothing to lose by starting over
+    if ic_cdk::api::stable::stable_size() == 0 {
+        init();
+        return;
+    }
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    // Trapping rolls the upgrade back rather than dropping the state this version cannot read
+    let state = decode_state(&ic_cdk::api::stable::stable_bytes())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode the saved state: {}", e)));
+    if state.version > STATE_VERSION {
+        ic_cdk::trap(&format!(
+            "State version {} is newer than {}",
+            state.version, STATE_VERSION
+        ));
+    }
+
+    admin::restore(state.admins.unwrap_or_default());
+    interval::restore(state.interval_settings.unwrap_or_default());
+    if let Some(reserve) = state.cycles_reserve {
+        cycles::set_reserve(reserve);
+    }
+
+    // Timers do not survive an upgrade, so re-arm the active jobs on their previous schedule
+    jobs::restore(state.jobs.unwrap_or_default());
+
+    count_instructions(start_instructions, "post_upgrade".to_string());
+}
+
+fn current_interval() -> Option<u64> {
+    match jobs::get::<Task>(DEFAULT_JOB).map(|job| job.schedule) {
+        Some(Schedule::Interval { seconds }) => Some(seconds),
+        _ => None,
+    }
 }
 
 #[query]
 fn get_interval() -> Result<u64, Error> {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    let res = INTERVAL_IN_SECONDS.with(|interval_ref| Ok(interval_ref.borrow().clone()));
+    let res = current_interval().ok_or_else(|| Error {
+        message: format!("Job {} does not run on an interval", DEFAULT_JOB),
+    });
 
     count_instructions(start_instructions, "get_interval".to_string());
 
     res
 }
 
+// The interval is checked against the configured bounds by the job registry
 #[update]
 fn set_interval(seconds: u64) -> Result<u64, Error> {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    TIMERS.with(|timers_ref| {
-        let timer_id = timers_ref.borrow().clone();
-        ic_cdk_timers::clear_timer(timer_id);
+    let res = admin::ensure_admin().and_then(|_| {
+        let previous_seconds = current_interval();
+        let schedule = Schedule::Interval { seconds };
+        jobs::set(DEFAULT_JOB.to_string(), Task::FetchQuote, schedule)?;
+        interval::record(previous_seconds, seconds);
+        Ok(seconds)
     });
 
-    let interval = std::time::Duration::from_secs(seconds);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    let new_timer_id = ic_cdk_timers::set_timer_interval(interval, || {
-        ic_cdk::spawn(call_http_outcall());
-    });
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(new_timer_id);
-    });
+    count_instructions(start_instructions, "set_interval".to_string());
+
+    res
+}
+
+#[query]
+fn timer_status() -> Result<TimerStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = jobs::status::<Task>(DEFAULT_JOB);
+
+    count_instructions(start_instructions, "timer_status".to_string());
+
+    res
+}
+
+#[update]
+fn pause_timer() -> Result<TimerStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin()
+        .and_then(|_| jobs::pause::<Task>(DEFAULT_JOB))
+        .and_then(|_| jobs::status::<Task>(DEFAULT_JOB));
+
+    count_instructions(start_instructions, "pause_timer".to_string());
+
+    res
+}
+
+#[update]
+fn resume_timer() -> Result<TimerStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin()
+        .and_then(|_| jobs::resume::<Task>(DEFAULT_JOB))
+        .and_then(|_| jobs::status::<Task>(DEFAULT_JOB));
+
+    count_instructions(start_instructions, "resume_timer".to_string());
+
+    res
+}
+
+#[query]
+fn get_interval_bounds() -> IntervalBounds {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = interval::bounds();
+
+    count_instructions(start_instructions, "get_interval_bounds".to_string());
+
+    res
+}
+
+#[update]
+fn set_interval_bounds(bounds: IntervalBounds) -> Result<IntervalBounds, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| interval::set_bounds(bounds));
+
+    count_instructions(start_instructions, "set_interval_bounds".to_string());
+
+    res
+}
+
+#[query]
+fn cycles_status() -> CyclesStatus {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = cycles::status();
+
+    count_instructions(start_instructions, "cycles_status".to_string());
+
+    res
+}
+
+// Jobs suspended by the cycles guard resume right away if the new reserve is met
+#[update]
+fn set_cycles_reserve(reserve: u128) -> Result<CyclesStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
 
-    INTERVAL_IN_SECONDS.with(|seconds_ref| {
-        seconds_ref.replace(seconds);
+    let res = admin::ensure_admin().map(|_| {
+        cycles::set_reserve(reserve);
+        jobs::resume_suspended::<Task>();
+        cycles::status()
     });
 
-    count_instructions(start_instructions, "set_interval".to_string());
+    count_instructions(start_instructions, "set_cycles_reserve".to_string());
+
+    res
+}
+
+#[query]
+fn get_interval_changes() -> Vec<IntervalChange> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = interval::changes();
+
+    count_instructions(start_instructions, "get_interval_changes".to_string());
+
+    res
+}
+
+#[update]
+fn add_admin(principal: Principal) -> Result<Vec<Principal>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_controller().map(|_| admin::add(principal));
+
+    count_instructions(start_instructions, "add_admin".to_string());
+
+    res
+}
+
+#[update]
+fn remove_admin(principal: Principal) -> Result<Vec<Principal>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_controller().map(|_| admin::remove(&principal));
+
+    count_instructions(start_instructions, "remove_admin".to_string());
+
+    res
+}
 
-    Ok(seconds)
+#[query]
+fn list_admins() -> Vec<Principal> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::list();
+
+    count_instructions(start_instructions, "list_admins".to_string());
+
+    res
+}
+
+#[update]
+fn register_job(name: String, task: Task, schedule: Schedule) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::register(name, task, schedule));
+
+    count_instructions(start_instructions, "register_job".to_string());
+
+    res
+}
+
+#[query]
+fn list_jobs() -> Vec<Job<Task>> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = jobs::list();
+
+    count_instructions(start_instructions, "list_jobs".to_string());
+
+    res
+}
+
+#[update]
+fn pause_job(name: String) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::pause(&name));
+
+    count_instructions(start_instructions, "pause_job".to_string());
+
+    res
+}
+
+#[update]
+fn resume_job(name: String) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::resume(&name));
+
+    count_instructions(start_instructions, "resume_job".to_string());
+
+    res
+}
+
+#[update]
+fn cancel_job(name: String) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::cancel(&name));
+
+    count_instructions(start_instructions, "cancel_job".to_string());
+
+    res
+}
+
+#[update]
+fn set_missed_tick_policy(name: String, policy: MissedTickPolicy) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::set_missed_tick_policy(&name, policy));
+
+    count_instructions(start_instructions, "set_missed_tick_policy".to_string());
+
+    res
 }
 
 // Enable Candid export
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canister_common::jobs::JobStatus;
+
+    #[test]
+    fn keeps_polling_after_an_upgrade() {
+        let job = Job {
+            name: DEFAULT_JOB.to_string(),
+            task: Task::FetchQuote,
+            schedule: Schedule::Interval { seconds: 60 },
+            status: JobStatus::Active,
+            created_at: 0,
+            last_run_at: Some(30),
+            next_run_at: Some(100),
+            run_count: 4,
+            last_error: None,
+            missed_tick_policy: MissedTickPolicy::CatchUp,
+            missed_ticks: 2,
+            drift_nanos: 0,
+        };
+        cycles::set_reserve(5);
+        let mut state = save_state();
+        state.jobs = Some(JobStore::from([(job.name.clone(), job)]));
+        let mut bytes = candid::encode_args((state,)).unwrap();
+        bytes.resize(64 * 1024, 0);
+
+        let state = decode_state(&bytes).unwrap();
+        assert_eq!(state.cycles_reserve, Some(5));
+        let jobs = state.jobs.unwrap();
+
+        let job = &jobs[DEFAULT_JOB];
+        assert!(job.status == JobStatus::Active);
+        assert!(job.schedule == Schedule::Interval { seconds: 60 });
+        assert_eq!(job.run_count, 4);
+        assert!(job.missed_tick_policy == MissedTickPolicy::CatchUp);
+        assert_eq!(job.missed_ticks, 2);
+        // The re-armed job keeps its pending run, or runs right away if that has passed
+        assert_eq!(jobs::due_at(job, 40).unwrap(), 100);
+        assert_eq!(jobs::due_at(job, 140).unwrap(), 140);
+    }
+
+    #[test]
+    fn refuses_state_it_cannot_read() {
+        assert!(decode_state(&[0; 64 * 1024]).is_err());
+        assert!(decode_state(b"DIDL\x00\x01\x7d\x07").is_err());
+    }
+}
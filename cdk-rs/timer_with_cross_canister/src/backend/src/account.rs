@@ -0,0 +1,264 @@
+use crate::{Block, Operation};
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha224};
+use std::fmt;
+
+pub type Subaccount = [u8; 32];
+
+// Subaccount of a principal's main account
+pub const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+const DOMAIN_SEPARATOR: &[u8] = b"\x0Aaccount-id";
+const CHECKSUM_BYTES: usize = 4;
+
+// The ledger's account identifier: a CRC32 checksum followed by the SHA-224 hash of
+// the principal and subaccount. Blocks refer to accounts by these 32 bytes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct AccountIdentifier([u8; 32]);
+
+impl AccountIdentifier {
+    pub fn new(owner: &Principal, subaccount: Option<&Subaccount>) -> Self {
+        let mut hasher = Sha224::new();
+        hasher.update(DOMAIN_SEPARATOR);
+        hasher.update(owner.as_slice());
+        hasher.update(subaccount.unwrap_or(&DEFAULT_SUBACCOUNT));
+        let hash = hasher.finalize();
+
+        let mut bytes = [0; 32];
+        bytes[..CHECKSUM_BYTES].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+        bytes[CHECKSUM_BYTES..].copy_from_slice(&hash);
+        AccountIdentifier(bytes)
+    }
+
+    // Accepts the 32 bytes of an identifier, rejecting them if the checksum does not match
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, String> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| {
+            format!(
+                "Account identifiers are 32 bytes long, got {} bytes",
+                bytes.len()
+            )
+        })?;
+        let (checksum, hash) = bytes.split_at(CHECKSUM_BYTES);
+        if checksum != crc32fast::hash(hash).to_be_bytes() {
+            return Err(format!(
+                "Account identifier {} has an invalid checksum",
+                hex::encode(bytes)
+            ));
+        }
+        Ok(AccountIdentifier(bytes))
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, String> {
+        let bytes =
+            hex::decode(text).map_err(|e| format!("Invalid account identifier {}: {}", text, e))?;
+        Self::from_slice(&bytes)
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for AccountIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+// An ICRC-1 account. The default subaccount is always stored as None, so that
+// every account has a single representation.
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn new(owner: Principal, subaccount: Option<Vec<u8>>) -> Result<Self, String> {
+        let subaccount = match subaccount {
+            Some(subaccount) if subaccount.len() != DEFAULT_SUBACCOUNT.len() => {
+                return Err(format!(
+                    "Subaccounts are 32 bytes long, got {} bytes",
+                    subaccount.len()
+                ));
+            }
+            Some(subaccount) if subaccount != DEFAULT_SUBACCOUNT => Some(subaccount),
+            _ => None,
+        };
+        Ok(Account { owner, subaccount })
+    }
+
+    // Parses the ICRC-1 textual encoding, `<owner>` for the default subaccount and
+    // `<owner>-<checksum>.<subaccount in hex without leading zeros>` otherwise
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let invalid = |reason: String| format!("Invalid account {}: {}", text, reason);
+        let Some((prefix, subaccount_hex)) = text.rsplit_once('.') else {
+            let owner = Principal::from_text(text).map_err(|e| invalid(e.to_string()))?;
+            return Ok(Account {
+                owner,
+                subaccount: None,
+            });
+        };
+        let (owner, checksum) = prefix
+            .rsplit_once('-')
+            .ok_or_else(|| invalid("missing checksum".to_string()))?;
+        let owner = Principal::from_text(owner).map_err(|e| invalid(e.to_string()))?;
+
+        if subaccount_hex.starts_with('0') || subaccount_hex.len() > 64 {
+            return Err(invalid("subaccount is not in canonical form".to_string()));
+        }
+        let subaccount =
+            hex::decode(format!("{:0>64}", subaccount_hex)).map_err(|e| invalid(e.to_string()))?;
+        let account = Account::new(owner, Some(subaccount)).map_err(invalid)?;
+        if account.subaccount.is_none() {
+            return Err(invalid(
+                "the default subaccount is written as the owner alone".to_string(),
+            ));
+        }
+        if checksum != account.checksum() {
+            return Err(invalid("checksum does not match".to_string()));
+        }
+        Ok(account)
+    }
+
+    pub fn to_text(&self) -> String {
+        match &self.subaccount {
+            None => self.owner.to_text(),
+            Some(subaccount) => {
+                let subaccount_hex = hex::encode(subaccount);
+                format!(
+                    "{}-{}.{}",
+                    self.owner,
+                    self.checksum(),
+                    subaccount_hex.trim_start_matches('0')
+                )
+            }
+        }
+    }
+
+    // Lowercase base32 of the CRC32 of the owner and subaccount bytes
+    fn checksum(&self) -> String {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(self.owner.as_slice());
+        hasher.update(self.subaccount.as_deref().unwrap_or(&DEFAULT_SUBACCOUNT));
+        data_encoding::BASE32_NOPAD
+            .encode(&hasher.finalize().to_be_bytes())
+            .to_lowercase()
+    }
+
+    pub fn account_identifier(&self) -> AccountIdentifier {
+        let subaccount: Option<Subaccount> = self
+            .subaccount
+            .as_deref()
+            .and_then(|subaccount| subaccount.try_into().ok());
+        AccountIdentifier::new(&self.owner, subaccount.as_ref())
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_text())
+    }
+}
+
+// The accounts a block's operation refers to, as the raw bytes stored in the block.
+// Spenders of transfers and burns only act on behalf of `from`, so they are left out
+// unless `include_spenders` is set. The spender of an approval is always included.
+pub fn block_accounts(block: &Block, include_spenders: bool) -> Vec<&[u8]> {
+    let Some(operation) = &block.transaction.operation else {
+        return Vec::new();
+    };
+    let (mut accounts, spender): (Vec<&[u8]>, _) = match operation {
+        Operation::Approve(approve) => (vec![&approve.from, &approve.spender], None),
+        Operation::Burn(burn) => (vec![&burn.from], burn.spender.as_deref()),
+        Operation::Mint(mint) => (vec![&mint.to], None),
+        Operation::Transfer(transfer) => (
+            vec![&transfer.from, &transfer.to],
+            transfer.spender.as_deref(),
+        ),
+    };
+    if include_spenders {
+        accounts.extend(spender);
+    }
+    accounts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The default account of the anonymous principal, as derived by the ledger
+    const ANONYMOUS: &str = "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79";
+
+    #[test]
+    fn derives_account_identifiers() {
+        let anonymous = Principal::anonymous();
+        assert_eq!(AccountIdentifier::new(&anonymous, None).to_hex(), ANONYMOUS);
+        assert_eq!(
+            AccountIdentifier::new(&anonymous, Some(&DEFAULT_SUBACCOUNT)).to_hex(),
+            ANONYMOUS
+        );
+        assert_ne!(
+            AccountIdentifier::new(&anonymous, Some(&[1; 32])).to_hex(),
+            ANONYMOUS
+        );
+    }
+
+    #[test]
+    fn parses_account_identifiers() {
+        let parsed = AccountIdentifier::from_hex(ANONYMOUS).unwrap();
+        assert_eq!(
+            parsed,
+            AccountIdentifier::new(&Principal::anonymous(), None)
+        );
+        assert_eq!(parsed.to_hex(), ANONYMOUS);
+
+        let bad_checksum = format!("0{}", &ANONYMOUS[1..]);
+        assert!(AccountIdentifier::from_hex(&bad_checksum).is_err());
+        assert!(AccountIdentifier::from_hex(&ANONYMOUS[2..]).is_err());
+        assert!(AccountIdentifier::from_hex("not hex").is_err());
+    }
+
+    const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+    #[test]
+    fn parses_textual_accounts() {
+        let owner = Principal::from_text(OWNER).unwrap();
+        let account = Account::from_text(&format!("{}-6cc627i.1", OWNER)).unwrap();
+        let mut subaccount = vec![0; 32];
+        subaccount[31] = 1;
+        assert_eq!(account, Account::new(owner, Some(subaccount)).unwrap());
+
+        let text = format!(
+            "{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+            OWNER
+        );
+        let account = Account::from_text(&text).unwrap();
+        assert_eq!(account.subaccount, Some((1..=32).collect()));
+        assert_eq!(account.to_text(), text);
+
+        let default = Account::from_text(OWNER).unwrap();
+        assert_eq!(default.subaccount, None);
+        assert_eq!(default.to_text(), OWNER);
+    }
+
+    #[test]
+    fn rejects_malformed_textual_accounts() {
+        // Checksum of another subaccount
+        assert!(Account::from_text(&format!("{}-6cc627i.2", OWNER)).is_err());
+        assert!(Account::from_text(&format!("{}.1", OWNER)).is_err());
+        // Leading zeros are not canonical
+        assert!(Account::from_text(&format!("{}-6cc627i.01", OWNER)).is_err());
+        // The default subaccount is written as the owner alone
+        let checksum = Account::new(Principal::from_text(OWNER).unwrap(), None)
+            .unwrap()
+            .checksum();
+        assert!(Account::from_text(&format!("{}-{}.0", OWNER, checksum)).is_err());
+        assert!(Account::from_text(&format!("{}-{}.", OWNER, checksum)).is_err());
+    }
+}
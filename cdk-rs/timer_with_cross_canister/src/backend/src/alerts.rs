@@ -0,0 +1,254 @@
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::{history, Error};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Periods are measured against the balance history, whose finest buckets are minutes
+// and which keeps a year of days
+const MIN_PERIOD_SECONDS: u64 = 60;
+const MAX_PERIOD_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+// Oldest alerts are dropped beyond this many
+const MAX_ALERTS: usize = 1_000;
+
+// Method called on a rule's subscriber with the triggered `Alert`
+const SUBSCRIBER_METHOD: &str = "on_balance_alert";
+
+pub type AlertState = (u64, BTreeMap<u64, AlertRule>, VecDeque<Alert>);
+
+thread_local! {
+    static NEXT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static RULES: RefCell<BTreeMap<u64, AlertRule>> = RefCell::default();
+    static ALERTS: RefCell<VecDeque<Alert>> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum AlertCondition {
+    Below { amount: u128 },
+    Above { amount: u128 },
+    Change { amount: u128, period_seconds: u64 },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AlertRule {
+    pub id: u64,
+    pub user_id: usize,
+    pub condition: AlertCondition,
+    pub subscriber: Option<Principal>, // Canister notified of every alert of the rule
+    pub created_at: u64,
+    pub last_triggered_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Alert {
+    pub id: u64,
+    pub rule_id: u64,
+    pub user_id: usize,
+    pub condition: AlertCondition,
+    pub previous_balance: u128, // The balance before the refresh, or at the start of the period
+    pub balance: u128,
+    pub triggered_at: u64,
+    pub acknowledged_at: Option<u64>,
+    pub delivery_error: Option<String>,
+}
+
+fn next_id() -> u64 {
+    NEXT_ID.with(|next_id| {
+        let id = *next_id.borrow();
+        next_id.replace(id + 1);
+        id
+    })
+}
+
+pub fn add_rule(
+    user_id: usize,
+    condition: AlertCondition,
+    subscriber: Option<Principal>,
+) -> Result<AlertRule, Error> {
+    if let AlertCondition::Change { period_seconds, .. } = condition {
+        if !(MIN_PERIOD_SECONDS..=MAX_PERIOD_SECONDS).contains(&period_seconds) {
+            return Err(Error {
+                message: format!(
+                    "Change periods must be between {} and {} seconds",
+                    MIN_PERIOD_SECONDS, MAX_PERIOD_SECONDS
+                ),
+            });
+        }
+    }
+    let rule = AlertRule {
+        id: next_id(),
+        user_id,
+        condition,
+        subscriber,
+        created_at: ic_cdk::api::time(),
+        last_triggered_at: None,
+    };
+    RULES.with(|rules| rules.borrow_mut().insert(rule.id, rule.clone()));
+    Ok(rule)
+}
+
+pub fn remove_rule(id: u64) -> Result<AlertRule, Error> {
+    RULES
+        .with(|rules| rules.borrow_mut().remove(&id))
+        .ok_or_else(|| Error {
+            message: format!("Alert rule {} not found", id),
+        })
+}
+
+pub fn rules() -> Vec<AlertRule> {
+    RULES.with(|rules| rules.borrow().values().cloned().collect())
+}
+
+// Triggered alerts, newest first
+pub fn alerts(unacknowledged_only: bool) -> Vec<Alert> {
+    ALERTS.with(|alerts| {
+        alerts
+            .borrow()
+            .iter()
+            .rev()
+            .filter(|alert| !unacknowledged_only || alert.acknowledged_at.is_none())
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn acknowledge(id: u64) -> Result<Alert, Error> {
+    ALERTS.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        let alert = alerts
+            .iter_mut()
+            .find(|alert| alert.id == id)
+            .ok_or_else(|| Error {
+                message: format!("Alert {} not found", id),
+            })?;
+        alert.acknowledged_at.get_or_insert(ic_cdk::api::time());
+        Ok(alert.clone())
+    })
+}
+
+// The balance the rule compares against, if its condition is met. Thresholds fire
+// when the balance crosses them, and a change fires at most once per period.
+fn triggered(rule: &AlertRule, previous: u128, balance: u128, now: u64) -> Option<u128> {
+    match rule.condition {
+        AlertCondition::Below { amount } => {
+            (previous >= amount && balance < amount).then_some(previous)
+        }
+        AlertCondition::Above { amount } => {
+            (previous <= amount && balance > amount).then_some(previous)
+        }
+        AlertCondition::Change {
+            amount,
+            period_seconds,
+        } => {
+            let period = period_seconds.saturating_mul(NANOS_PER_SECOND);
+            if rule
+                .last_triggered_at
+                .is_some_and(|at| now < at.saturating_add(period))
+            {
+                return None;
+            }
+            let start =
+                history::amount_at(rule.user_id, now.saturating_sub(period)).unwrap_or(previous);
+            (balance.abs_diff(start) > amount).then_some(start)
+        }
+    }
+}
+
+// Evaluates the user's rules after a refresh changed the balance from `previous`,
+// storing an alert for every rule that fires and notifying its subscriber
+pub fn evaluate(user_id: usize, previous: u128, balance: u128, now: u64) {
+    let fired: Vec<(AlertRule, u128)> = RULES.with(|rules| {
+        rules
+            .borrow_mut()
+            .values_mut()
+            .filter(|rule| rule.user_id == user_id)
+            .filter_map(|rule| {
+                let compared = triggered(rule, previous, balance, now)?;
+                rule.last_triggered_at = Some(now);
+                Some((rule.clone(), compared))
+            })
+            .collect()
+    });
+
+    for (rule, compared) in fired {
+        let mut alert = Alert {
+            id: next_id(),
+            rule_id: rule.id,
+            user_id,
+            condition: rule.condition,
+            previous_balance: compared,
+            balance,
+            triggered_at: now,
+            acknowledged_at: None,
+            delivery_error: None,
+        };
+        if let Some(subscriber) = rule.subscriber {
+            // One-way call, so a slow or failing subscriber never holds up a refresh
+            let result = ic_cdk::api::call::notify(subscriber, SUBSCRIBER_METHOD, (alert.clone(),));
+            alert.delivery_error = result
+                .err()
+                .map(|code| format!("Notifying {} failed: {:?}", subscriber, code));
+        }
+        ALERTS.with(|alerts| {
+            let mut alerts = alerts.borrow_mut();
+            alerts.push_back(alert);
+            if alerts.len() > MAX_ALERTS {
+                alerts.pop_front();
+            }
+        });
+    }
+}
+
+pub fn save() -> AlertState {
+    (
+        NEXT_ID.with(|next_id| *next_id.borrow()),
+        RULES.with(|rules| rules.borrow().clone()),
+        ALERTS.with(|alerts| alerts.borrow().clone()),
+    )
+}
+
+pub fn restore((next_id, rules, alerts): AlertState) {
+    NEXT_ID.with(|next_id_ref| next_id_ref.replace(next_id));
+    RULES.with(|rules_ref| rules_ref.replace(rules));
+    ALERTS.with(|alerts_ref| alerts_ref.replace(alerts));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_change_periods() {
+        let change = |period_seconds| AlertCondition::Change {
+            amount: 1,
+            period_seconds,
+        };
+        assert!(add_rule(1, change(MIN_PERIOD_SECONDS - 1), None).is_err());
+        assert!(add_rule(1, change(MAX_PERIOD_SECONDS + 1), None).is_err());
+        assert!(add_rule(1, change(u64::MAX), None).is_err());
+    }
+
+    #[test]
+    fn saturates_long_periods() {
+        let rule = AlertRule {
+            id: 1,
+            user_id: 1,
+            condition: AlertCondition::Change {
+                amount: 10,
+                period_seconds: u64::MAX,
+            },
+            subscriber: None,
+            created_at: 0,
+            last_triggered_at: Some(5),
+        };
+        assert_eq!(triggered(&rule, 0, 100, u64::MAX - 1), None);
+        let rule = AlertRule {
+            last_triggered_at: None,
+            ..rule
+        };
+        assert_eq!(triggered(&rule, 0, 100, 1_000), Some(0));
+    }
+}
@@ -0,0 +1,392 @@
+use crate::account::AccountIdentifier;
+use crate::{
+    call_context_count_instructions, config, transactions, Approve, Block, Burn, E8s, Mint, Task,
+    Timestamp, Transfer,
+};
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::{jobs, Error};
+use ic_cdk::api::call::CallResult;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, VecDeque};
+
+// Transactions requested per call to the index canister
+const PAGE_SIZE: u64 = 500;
+
+// Pages fetched per tick, the remaining accounts are left to the following ticks
+const MAX_PAGES_PER_TICK: u64 = 10;
+
+// The pending accounts and the users whose accounts were completed
+pub type BackfillState = (VecDeque<Backfill>, BTreeSet<usize>);
+
+thread_local! {
+    static PENDING: RefCell<VecDeque<Backfill>> = RefCell::default();
+    static COMPLETED: RefCell<BTreeSet<usize>> = RefCell::default();
+    // Not persisted, as calls outstanding during an upgrade never complete
+    static IN_PROGRESS: RefCell<bool> = RefCell::default();
+}
+
+// An account whose history is still being read from the index canister, newest first
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Backfill {
+    pub user_id: usize,
+    pub account_identifier: String,
+    pub start: Option<u64>, // Oldest transaction fetched so far, the next page starts below it
+    pub fetched: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct BackfillStatus {
+    pub index_canister_id: Option<Principal>,
+    pub in_progress: bool,
+    pub completed: u64,
+    pub pending: Vec<Backfill>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountIdentifierTransactionsArgs {
+    max_results: u64,
+    start: Option<u64>,
+    account_identifier: String,
+}
+
+#[derive(CandidType, Deserialize)]
+enum GetAccountIdentifierTransactionsResult {
+    Ok(GetAccountIdentifierTransactionsResponse),
+    Err(GetAccountIdentifierTransactionsError),
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountIdentifierTransactionsResponse {
+    transactions: Vec<TransactionWithId>,
+    oldest_tx_id: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountIdentifierTransactionsError {
+    message: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransactionWithId {
+    id: u64,
+    transaction: Transaction,
+}
+
+// The index canister's transaction, which refers to accounts by hex identifiers
+#[derive(CandidType, Deserialize)]
+struct Transaction {
+    memo: u64,
+    icrc1_memo: Option<Vec<u8>>,
+    operation: Operation,
+    created_at_time: Option<Timestamp>,
+    timestamp: Option<Timestamp>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum Operation {
+    Approve {
+        fee: E8s,
+        from: String,
+        allowance: E8s,
+        expected_allowance: Option<E8s>,
+        expires_at: Option<Timestamp>,
+        spender: String,
+    },
+    Burn {
+        from: String,
+        amount: E8s,
+        spender: Option<String>,
+    },
+    Mint {
+        to: String,
+        amount: E8s,
+    },
+    Transfer {
+        to: String,
+        fee: E8s,
+        from: String,
+        amount: E8s,
+        spender: Option<String>,
+    },
+}
+
+pub fn in_progress() -> bool {
+    IN_PROGRESS.with(|in_progress| *in_progress.borrow())
+}
+
+pub fn status() -> BackfillStatus {
+    BackfillStatus {
+        index_canister_id: config::index_canister_id(),
+        in_progress: in_progress(),
+        completed: COMPLETED.with(|completed| completed.borrow().len() as u64),
+        pending: PENDING.with(|pending| pending.borrow().iter().cloned().collect()),
+    }
+}
+
+// Queues the users that are neither pending nor completed, such as the users added
+// before the canister backfilled account histories
+pub fn enqueue_missing(users: impl IntoIterator<Item = (usize, AccountIdentifier)>) {
+    let pending: BTreeSet<usize> =
+        PENDING.with(|pending| pending.borrow().iter().map(|b| b.user_id).collect());
+    let completed = COMPLETED.with(|completed| completed.borrow().clone());
+    for (user_id, account) in users {
+        if !pending.contains(&user_id) && !completed.contains(&user_id) {
+            enqueue(user_id, account);
+        }
+    }
+}
+
+pub fn enqueue(user_id: usize, account: AccountIdentifier) {
+    PENDING.with(|pending| {
+        pending.borrow_mut().push_back(Backfill {
+            user_id,
+            account_identifier: account.to_hex(),
+            start: None,
+            fetched: 0,
+            last_error: None,
+        })
+    });
+}
+
+// Marks a backfill as running until it is dropped. A call whose callback traps drops
+// it too, when the cleanup drops the backfilling future.
+struct Running;
+
+impl Running {
+    fn start() -> Option<Self> {
+        let running = IN_PROGRESS.with(|in_progress| in_progress.replace(true));
+        (!running).then_some(Running)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        IN_PROGRESS.with(|in_progress| in_progress.replace(false));
+    }
+}
+
+// Reads the pending accounts' histories from the index canister. Without an index
+// canister, or while it fails, transactions only come from the ingested blocks.
+pub async fn run(job: String) {
+    let Some(index_canister_id) = config::index_canister_id() else {
+        return;
+    };
+    let Some(_running) = Running::start() else {
+        return;
+    };
+
+    for _ in 0..MAX_PAGES_PER_TICK {
+        let Some(backfill) = PENDING.with(|pending| pending.borrow().front().cloned()) else {
+            break;
+        };
+        if let Err(e) = backfill_page(index_canister_id, &backfill).await {
+            // Moved to the back, so that a failing account does not hold up the others
+            PENDING.with(|pending| {
+                let mut pending = pending.borrow_mut();
+                if let Some(mut backfill) = pending.pop_front() {
+                    backfill.last_error = Some(e.message.clone());
+                    pending.push_back(backfill);
+                }
+            });
+            let msg = format!(
+                "Backfilling user {} failed: {}",
+                backfill.user_id, e.message
+            );
+            jobs::report_error::<Task>(&job, msg);
+            break;
+        }
+    }
+}
+
+// Fetches the next page of the account at the front of the queue, dropping the
+// account from the queue once its oldest transaction has been read
+async fn backfill_page(index_canister_id: Principal, backfill: &Backfill) -> Result<(), Error> {
+    let response = get_account_identifier_transactions(
+        index_canister_id,
+        backfill.account_identifier.clone(),
+        backfill.start,
+    )
+    .await?;
+
+    let mut blocks = Vec::new();
+    for transaction in response.transactions {
+        let block = to_block(transaction.transaction).map_err(|message| Error {
+            message: format!("Transaction {}: {}", transaction.id, message),
+        })?;
+        blocks.push((transaction.id, block));
+    }
+    let oldest = blocks.last().map(|(id, _)| *id);
+    let done = oldest.is_none() || oldest == response.oldest_tx_id;
+    let fetched = blocks.len() as u64;
+    for (id, block) in blocks {
+        transactions::backfill(id, block);
+    }
+
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if done {
+            pending.pop_front();
+            COMPLETED.with(|completed| completed.borrow_mut().insert(backfill.user_id));
+        } else if let Some(front) = pending.front_mut() {
+            front.start = oldest;
+            front.fetched += fetched;
+            front.last_error = None;
+        }
+    });
+    Ok(())
+}
+
+fn account_bytes(account_identifier: &str) -> Result<Vec<u8>, String> {
+    AccountIdentifier::from_hex(account_identifier).map(|account| account.as_bytes().to_vec())
+}
+
+// Rebuilds the ledger block the index canister's transaction was read from
+fn to_block(transaction: Transaction) -> Result<Block, String> {
+    let operation = match transaction.operation {
+        Operation::Approve {
+            fee,
+            from,
+            allowance,
+            expected_allowance,
+            expires_at,
+            spender,
+        } => crate::Operation::Approve(Approve {
+            fee,
+            from: account_bytes(&from)?,
+            allowance_e8s: candid::Int::from(allowance.e8s),
+            allowance,
+            expected_allowance,
+            expires_at,
+            spender: account_bytes(&spender)?,
+        }),
+        Operation::Burn {
+            from,
+            amount,
+            spender,
+        } => crate::Operation::Burn(Burn {
+            from: account_bytes(&from)?,
+            amount,
+            spender: spender.as_deref().map(account_bytes).transpose()?,
+        }),
+        Operation::Mint { to, amount } => crate::Operation::Mint(Mint {
+            to: account_bytes(&to)?,
+            amount,
+        }),
+        Operation::Transfer {
+            to,
+            fee,
+            from,
+            amount,
+            spender,
+        } => crate::Operation::Transfer(Transfer {
+            to: account_bytes(&to)?,
+            fee,
+            from: account_bytes(&from)?,
+            amount,
+            spender: spender.as_deref().map(account_bytes).transpose()?,
+        }),
+    };
+    let created_at_time = transaction
+        .created_at_time
+        .unwrap_or(Timestamp { timestamp_nanos: 0 });
+    Ok(Block {
+        timestamp: transaction.timestamp.unwrap_or(created_at_time.clone()),
+        transaction: crate::Transaction {
+            memo: transaction.memo,
+            icrc1_memo: transaction.icrc1_memo,
+            operation: Some(operation),
+            created_at_time,
+        },
+        parent_hash: None,
+    })
+}
+
+async fn get_account_identifier_transactions(
+    index_canister_id: Principal,
+    account_identifier: String,
+    start: Option<u64>,
+) -> Result<GetAccountIdentifierTransactionsResponse, Error> {
+    let call_start_instructions = ic_cdk::api::call_context_instruction_counter();
+
+    let req = GetAccountIdentifierTransactionsArgs {
+        max_results: PAGE_SIZE,
+        start,
+        account_identifier,
+    };
+    let call_result: CallResult<(GetAccountIdentifierTransactionsResult,)> = ic_cdk::call(
+        index_canister_id,
+        "get_account_identifier_transactions",
+        (req,),
+    )
+    .await;
+
+    call_context_count_instructions(
+        call_start_instructions,
+        "ic_cdk::call get_account_identifier_transactions".to_string(),
+    );
+
+    match call_result {
+        Ok((GetAccountIdentifierTransactionsResult::Ok(response),)) => Ok(response),
+        Ok((GetAccountIdentifierTransactionsResult::Err(e),)) => Err(Error {
+            message: format!("get_account_identifier_transactions failed: {}", e.message),
+        }),
+        Err((code, message)) => Err(Error {
+            message: format!(
+                "get_account_identifier_transactions failed: {:?} {}",
+                code, message
+            ),
+        }),
+    }
+}
+
+pub fn save() -> BackfillState {
+    (
+        PENDING.with(|pending| pending.borrow().clone()),
+        COMPLETED.with(|completed| completed.borrow().clone()),
+    )
+}
+
+pub fn restore((pending, completed): BackfillState) {
+    PENDING.with(|pending_ref| pending_ref.replace(pending));
+    COMPLETED.with(|completed_ref| completed_ref.replace(completed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_users_without_a_backfill() {
+        let accounts: Vec<(usize, AccountIdentifier)> = (1..=3u8)
+            .map(|id| {
+                let account = AccountIdentifier::new(&Principal::anonymous(), Some(&[id; 32]));
+                (id as usize, account)
+            })
+            .collect();
+        enqueue(1, accounts[0].1);
+        restore((save().0, BTreeSet::from([2])));
+
+        enqueue_missing(accounts.clone());
+        enqueue_missing(accounts);
+        let (pending, completed) = save();
+        let queued: Vec<usize> = pending.iter().map(|backfill| backfill.user_id).collect();
+        assert_eq!(queued, vec![1, 3]);
+        assert_eq!(completed, BTreeSet::from([2]));
+    }
+
+    #[test]
+    fn stops_running_when_the_run_is_dropped() {
+        let running = Running::start();
+        assert!(running.is_some());
+        assert!(in_progress());
+        // A tick arriving meanwhile leaves the run alone
+        assert!(Running::start().is_none());
+
+        // As when a callback traps and the backfilling future is dropped
+        drop(running);
+        assert!(!in_progress());
+        assert!(Running::start().is_some());
+    }
+}
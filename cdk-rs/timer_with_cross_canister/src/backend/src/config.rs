@@ -0,0 +1,94 @@
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::Error;
+use serde::Serialize;
+use std::cell::RefCell;
+
+// The mainnet ICP ledger, used unless another ledger is configured
+const DEFAULT_LEDGER_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+
+thread_local! {
+    static CONFIG: RefCell<LedgerConfig> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct LedgerConfig {
+    pub ledger_canister_id: Principal,
+    pub index_canister_id: Option<Principal>,
+}
+
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            ledger_canister_id: DEFAULT_LEDGER_CANISTER_ID,
+            index_canister_id: None,
+        }
+    }
+}
+
+// Passed on install and upgrade. Fields left out keep their current value.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct InitArgs {
+    pub ledger_canister_id: Option<Principal>,
+    pub index_canister_id: Option<Principal>,
+}
+
+pub fn get() -> LedgerConfig {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+pub fn set(config: LedgerConfig) -> LedgerConfig {
+    CONFIG.with(|config_ref| config_ref.replace(config.clone()));
+    config
+}
+
+pub fn ledger_canister_id() -> Principal {
+    CONFIG.with(|config| config.borrow().ledger_canister_id)
+}
+
+pub fn index_canister_id() -> Option<Principal> {
+    CONFIG.with(|config| config.borrow().index_canister_id)
+}
+
+// Stored blocks belong to the configured ledger, so another ledger may only be
+// configured before any block was read
+pub fn ensure_ledger_can_change(
+    ledger_canister_id: Principal,
+    blocks_read: bool,
+) -> Result<(), Error> {
+    let current = self::ledger_canister_id();
+    if ledger_canister_id == current || !blocks_read {
+        return Ok(());
+    }
+    Err(Error {
+        message: format!(
+            "Blocks of ledger {} have already been read, it cannot be replaced by {}",
+            current, ledger_canister_id
+        ),
+    })
+}
+
+pub fn apply(args: InitArgs) {
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        if let Some(ledger_canister_id) = args.ledger_canister_id {
+            config.ledger_canister_id = ledger_canister_id;
+        }
+        if let Some(index_canister_id) = args.index_canister_id {
+            config.index_canister_id = Some(index_canister_id);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_ledger_once_blocks_were_read() {
+        let other = Principal::anonymous();
+        assert!(ensure_ledger_can_change(DEFAULT_LEDGER_CANISTER_ID, true).is_ok());
+        assert!(ensure_ledger_can_change(other, false).is_ok());
+        assert!(ensure_ledger_can_change(other, true).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{query_archive, query_blocks, stable, transactions, ArchivedBlock, Block, Task};
+use candid::{CandidType, Deserialize};
+use canister_common::{jobs, Error};
+use serde::Serialize;
+use std::cell::RefCell;
+
+// Blocks requested per call, the ledger returns at most 2000
+const PAGE_SIZE: u64 = 1_000;
+
+// Pages fetched per tick, the rest of the chain is left to the following ticks
+const MAX_PAGES_PER_TICK: u64 = 10;
+
+thread_local! {
+    static STATE: RefCell<IngestionState> = RefCell::default();
+    // Not persisted, as calls outstanding during an upgrade never complete
+    static IN_PROGRESS: RefCell<bool> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct IngestionState {
+    chain_length: Option<u64>, // As last reported by the ledger
+    last_ingested_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct IngestionStatus {
+    pub next_block_index: u64,
+    pub chain_length: Option<u64>,
+    pub caught_up: bool,
+    pub in_progress: bool,
+    pub last_ingested_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+pub fn in_progress() -> bool {
+    IN_PROGRESS.with(|in_progress| *in_progress.borrow())
+}
+
+pub fn status() -> IngestionStatus {
+    let state = STATE.with(|state| state.borrow().clone());
+    let next_block_index = stable::block_count();
+    IngestionStatus {
+        next_block_index,
+        chain_length: state.chain_length,
+        caught_up: state.chain_length == Some(next_block_index),
+        in_progress: in_progress(),
+        last_ingested_at: state.last_ingested_at,
+        last_error: state.last_error,
+    }
+}
+
+// Marks ingestion as running until it is dropped. A call whose callback traps drops
+// it too, when the cleanup drops the ingesting future.
+struct Running;
+
+impl Running {
+    fn start() -> Option<Self> {
+        let running = IN_PROGRESS.with(|in_progress| in_progress.replace(true));
+        (!running).then_some(Running)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        IN_PROGRESS.with(|in_progress| in_progress.replace(false));
+    }
+}
+
+// Pages through the ledger from `next_block_index` until caught up with its chain
+pub async fn ingest(job: String) {
+    let Some(_running) = Running::start() else {
+        return;
+    };
+
+    for _ in 0..MAX_PAGES_PER_TICK {
+        match ingest_page().await {
+            Ok(true) => continue,
+            Ok(false) => break,
+            Err(e) => {
+                STATE.with(|state| state.borrow_mut().last_error = Some(e.message.clone()));
+                jobs::report_error::<Task>(&job, e.message);
+                break;
+            }
+        }
+    }
+}
+
+// Stores the next page of blocks, returning whether the chain holds further blocks
+async fn ingest_page() -> Result<bool, Error> {
+    let start = stable::block_count();
+    let response = query_blocks(start, PAGE_SIZE).await?;
+    STATE.with(|state| state.borrow_mut().chain_length = Some(response.chain_length));
+
+    if response.blocks.is_empty() && response.archived_blocks.is_empty() {
+        return Ok(false);
+    }
+
+    // The ledger only returns the blocks it still holds, older ones are read from its archives
+    let mut archived = response.archived_blocks;
+    archived.sort_by_key(|range| range.start);
+    for range in &archived {
+        ingest_archived(range).await?;
+    }
+
+    if !response.blocks.is_empty() {
+        let next_block_index = stable::block_count();
+        if response.first_block_index != next_block_index {
+            return Err(Error {
+                message: format!(
+                    "Blocks {}..{} are missing from both the ledger and its archives",
+                    next_block_index, response.first_block_index
+                ),
+            });
+        }
+        for block in &response.blocks {
+            store(block)?;
+        }
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.last_ingested_at = Some(ic_cdk::api::time());
+        state.last_error = None;
+    });
+    Ok(stable::block_count() < response.chain_length)
+}
+
+// Stores an archived range, calling its archive again while it returns fewer blocks than asked for
+async fn ingest_archived(range: &ArchivedBlock) -> Result<(), Error> {
+    let end = range.start + range.length;
+    loop {
+        let next_block_index = stable::block_count();
+        if next_block_index >= end {
+            return Ok(());
+        }
+        if next_block_index < range.start {
+            return Err(Error {
+                message: format!(
+                    "Blocks {}..{} are missing from both the ledger and its archives",
+                    next_block_index, range.start
+                ),
+            });
+        }
+        let blocks =
+            query_archive(&range.callback, next_block_index, end - next_block_index).await?;
+        if blocks.is_empty() {
+            return Err(Error {
+                message: format!(
+                    "Archive {} returned no blocks from {}",
+                    range.callback.0.principal, next_block_index
+                ),
+            });
+        }
+        for block in blocks.iter().take((end - next_block_index) as usize) {
+            store(block)?;
+        }
+    }
+}
+
+// Appends the block and lists it in the history of its accounts
+fn store(block: &Block) -> Result<(), Error> {
+    let index = stable::append_block(block)?;
+    transactions::index(index, block);
+    Ok(())
+}
+
+pub fn save() -> IngestionState {
+    STATE.with(|state| state.borrow().clone())
+}
+
+pub fn restore(state: IngestionState) {
+    STATE.with(|state_ref| state_ref.replace(state));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_running_when_the_run_is_dropped() {
+        let running = Running::start();
+        assert!(running.is_some());
+        assert!(in_progress());
+        // A tick arriving meanwhile leaves the run alone
+        assert!(Running::start().is_none());
+
+        // As when a callback traps and the ingesting future is dropped
+        drop(running);
+        assert!(!in_progress());
+        assert!(Running::start().is_some());
+    }
+}
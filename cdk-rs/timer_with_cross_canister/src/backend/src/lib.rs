@@ -1,32 +1,79 @@
+use account::{Account, AccountIdentifier};
+use alerts::{Alert, AlertCondition, AlertRule, AlertState};
+use backfill::{BackfillState, BackfillStatus};
+use candid::de::IDLDeserialize;
 use candid::{CandidType, Deserialize, Principal};
+use canister_common::cycles::{self, CyclesStatus};
+use canister_common::history::{self, Bucket, HistoryStore, Resolution};
+use canister_common::interval::{self, IntervalBounds, IntervalChange, IntervalState};
+use canister_common::jobs::{self, Job, JobStore, MissedTickPolicy, Schedule, TimerStatus};
+use canister_common::{admin, leaderboard, Error};
+use config::{InitArgs, LedgerConfig};
 use ic_cdk::api::call::CallResult;
 use ic_cdk_macros::*;
+use ingestion::{IngestionState, IngestionStatus};
+use refresh::{RefreshState, RefreshStatus};
 use serde::Serialize;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use transactions::{BackfilledBlocks, TransactionIndex, TransactionPage};
+
+mod account;
+mod alerts;
+mod backfill;
+mod config;
+mod ingestion;
+mod refresh;
+mod stable;
+mod transactions;
 
 type UserStore = BTreeMap<usize, User>;
 
+// Version of the state saved by `pre_upgrade`, bumped when a field changes meaning.
+// Fields are optional, so that state saved before a field was added still decodes.
+const STATE_VERSION: u32 = 1;
+
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+    version: u32,
+    jobs: Option<JobStore<Task>>,
+    users: Option<UserStore>,
+    admins: Option<Vec<Principal>>,
+    interval_settings: Option<IntervalState>,
+    refresh: Option<RefreshState>,
+    cycles_reserve: Option<u128>,
+    history: Option<HistoryStore>,
+    ledger_config: Option<LedgerConfig>,
+    ingestion: Option<IngestionState>,
+    transactions: Option<TransactionIndex>,
+    alerts: Option<AlertState>,
+    backfill: Option<BackfillState>,
+    backfilled_blocks: Option<BackfilledBlocks>,
+}
+
 thread_local! {
     static USERS: RefCell<UserStore> = RefCell::default();
-    static INTERVAL_IN_SECONDS: RefCell<u64> = RefCell::default();
-    static TIMERS: RefCell<ic_cdk_timers::TimerId> = RefCell::default();
+    // Exact lookup of a user by account, rebuilt from USERS after an upgrade
+    static USER_IDS: RefCell<BTreeMap<Account, usize>> = RefCell::default();
+    // Lookup of a user by the account identifier of their account, used to match blocks
+    static ACCOUNT_IDS: RefCell<BTreeMap<AccountIdentifier, usize>> = RefCell::default();
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 struct User {
     principal: Principal,
+    subaccount: Option<Vec<u8>>, // None for the principal's default account
     balance: u128,
+    last_refreshed_at: Option<u64>, // When the balance was last read from the ledger
 }
 
-#[derive(CandidType, Deserialize, Serialize)]
-struct Error {
-    message: String,
-}
-
-#[derive(CandidType, Deserialize, Serialize, Clone)]
-struct BalanceOfQueryRequest {
-    owner: Principal,
+impl User {
+    fn account(&self) -> Account {
+        Account {
+            owner: self.principal,
+            subaccount: self.subaccount.clone(),
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -35,9 +82,7 @@ struct QueryBlocksQueryRequest {
     length: u64,
 }
 
-use std::collections::HashMap;
-
-#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
 pub struct Response {
     pub certificate: Option<Vec<u8>>,
     pub blocks: Vec<Block>,
@@ -78,7 +123,7 @@ pub enum Operation {
 pub struct Approve {
     pub fee: E8s,
     pub from: Vec<u8>,
-    pub allowance_e8s: i64,
+    pub allowance_e8s: candid::Int,
     pub allowance: E8s,
     pub expected_allowance: Option<E8s>,
     pub expires_at: Option<Timestamp>,
@@ -112,21 +157,24 @@ pub struct E8s {
     pub e8s: u64,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+// Archived blocks are served by the archive canister behind `callback`
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
 pub struct ArchivedBlock {
-    pub callback: HashMap<String, Callback>,
+    pub callback: QueryArchiveFn,
     pub start: u64,
     pub length: u64,
 }
 
+candid::define_function!(pub QueryArchiveFn : (QueryBlocksQueryRequest) -> (QueryArchiveResult) query);
+
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub enum Callback {
+pub enum QueryArchiveResult {
     Ok { blocks: Vec<Block> },
-    Err(CallbackError),
+    Err(QueryArchiveError),
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub enum CallbackError {
+pub enum QueryArchiveError {
     BadFirstBlockIndex {
         requested_index: u64,
         first_valid_index: u64,
@@ -153,16 +201,11 @@ fn call_context_count_instructions(start_counter: u64, function_name: String) {
     );
 }
 
-const LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
-
-async fn call_query_blocks() {
+async fn query_blocks(start: u64, length: u64) -> Result<Response, Error> {
     let call_start_instructions = ic_cdk::api::call_context_instruction_counter();
 
-    let ledger_principal = Principal::from_text(LEDGER_CANISTER_ID).expect("Invalid principal");
-    let req = QueryBlocksQueryRequest {
-        start: 0,
-        length: 100,
-    };
+    let ledger_principal = config::ledger_canister_id();
+    let req = QueryBlocksQueryRequest { start, length };
     let call_result: CallResult<(Response,)> =
         ic_cdk::call(ledger_principal, "query_blocks", (req,)).await;
 
@@ -171,152 +214,682 @@ async fn call_query_blocks() {
         "ic_cdk::call query_blocks".to_string(),
     );
 
-    let _ = call_result.map_err(|e| {
-        ic_cdk::println!("An error occurred: {:?}", e);
-    });
+    call_result
+        .map(|(response,)| response)
+        .map_err(|(code, message)| Error {
+            message: format!("query_blocks failed: {:?} {}", code, message),
+        })
+}
+
+// Reads blocks from the archive canister that holds them
+async fn query_archive(
+    callback: &QueryArchiveFn,
+    start: u64,
+    length: u64,
+) -> Result<Vec<Block>, Error> {
+    let call_start_instructions = ic_cdk::api::call_context_instruction_counter();
+
+    let archive = &callback.0;
+    let req = QueryBlocksQueryRequest { start, length };
+    let call_result: CallResult<(QueryArchiveResult,)> =
+        ic_cdk::call(archive.principal, &archive.method, (req,)).await;
+
+    call_context_count_instructions(
+        call_start_instructions,
+        format!("ic_cdk::call {}", archive.method),
+    );
+
+    let (result,) = call_result.map_err(|(code, message)| Error {
+        message: format!(
+            "{} on archive {} failed: {:?} {}",
+            archive.method, archive.principal, code, message
+        ),
+    })?;
+    match result {
+        QueryArchiveResult::Ok { blocks } => Ok(blocks),
+        QueryArchiveResult::Err(QueryArchiveError::BadFirstBlockIndex {
+            requested_index,
+            first_valid_index,
+        }) => Err(Error {
+            message: format!(
+                "Archive {} does not hold block {}, its first block is {}",
+                archive.principal, requested_index, first_valid_index
+            ),
+        }),
+        QueryArchiveResult::Err(QueryArchiveError::Other {
+            error_message,
+            error_code,
+        }) => Err(Error {
+            message: format!(
+                "Archive {} failed with code {}: {}",
+                archive.principal, error_code, error_message
+            ),
+        }),
+    }
 }
 
-async fn update_users(user: User) {
-    let start_instructions = ic_cdk::api::call_context_instruction_counter();
-    let ledger_principal = Principal::from_text(LEDGER_CANISTER_ID).expect("Invalid principal");
+async fn fetch_balance(account: Account) -> Result<u128, String> {
+    let ledger_principal = config::ledger_canister_id();
 
     let call_start_instructions = ic_cdk::api::call_context_instruction_counter();
-    let req = BalanceOfQueryRequest {
-        owner: user.principal.clone(),
-    };
     let call_result: CallResult<(u128,)> =
-        ic_cdk::call(ledger_principal, "icrc1_balance_of", (req,)).await;
+        ic_cdk::call(ledger_principal, "icrc1_balance_of", (account,)).await;
 
     call_context_count_instructions(
         call_start_instructions,
         "ic_cdk::call icrc1_balance_of".to_string(),
     );
 
-    USERS.with(|_users| {
-        let mut mutable_users = _users.borrow_mut();
-        let old_user = mutable_users
-            .clone()
-            .into_iter() // This gives us an iterator over the key-value pairs
-            .find(|(_key, _user)| {
-                _user
-                    .principal
-                    .to_string()
-                    .contains(&user.principal.to_string())
-            }); // Find the first user that matches the condition
-
-        if let Some((key, _user)) = old_user {
-            let response = call_result.map_err(|e| {
-                ic_cdk::println!("An error occurred: {:?}", e);
-            });
-
-            let mut new_user = _user;
-            match response {
-                Ok(response) => {
-                    new_user.balance = response.0;
-                    mutable_users.remove(&key.clone());
-                    mutable_users.insert(key, new_user);
-                } // Update new value of icrc1 balance for each user
-                _ => ic_cdk::println!("An error occurred while getting balance"),
-            };
-        } else {
-            ic_cdk::println!("No matching user found.");
-        }
+    call_result
+        .map(|(balance,)| balance)
+        .map_err(|(code, message)| format!("icrc1_balance_of failed: {:?} {}", code, message))
+}
+
+// Updates the icrc1 balance of the user in place and evaluates their alert rules
+fn set_balance(user_id: usize, balance: u128, now: u64) {
+    let previous = USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let user = users.get_mut(&user_id)?;
+        leaderboard::update(user_id, Some(user.balance), balance);
+        history::record(user_id, balance, now);
+        let previous = user.last_refreshed_at.map(|_| user.balance);
+        user.balance = balance;
+        user.last_refreshed_at = Some(now);
+        previous
     });
+    // The first refresh replaces the initial balance rather than changing it
+    if let Some(previous) = previous {
+        alerts::evaluate(user_id, previous, balance, now);
+    }
+}
+
+const DEFAULT_JOB: &str = "poll_ledger";
+const DEFAULT_INTERVAL_IN_SECONDS: u64 = 15;
+
+// Work that can be scheduled through the job registry
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+enum Task {
+    PollLedger,
+}
 
-    call_context_count_instructions(start_instructions, "update_users".to_string());
+impl jobs::Task for Task {
+    fn run(&self, job: &str, _ticks: u64) {
+        match self {
+            Task::PollLedger => poll_ledger(job),
+        }
+    }
+}
+
+// Whether the account identifier's bytes belong to a tracked user
+fn is_tracked(bytes: &[u8]) -> bool {
+    AccountIdentifier::from_slice(bytes)
+        .is_ok_and(|account| ACCOUNT_IDS.with(|ids| ids.borrow().contains_key(&account)))
+}
+
+// Tracked users whose accounts the block refers to
+fn block_users(block: &Block) -> Vec<usize> {
+    ACCOUNT_IDS.with(|ids| {
+        let ids = ids.borrow();
+        let mut user_ids: Vec<usize> = account::block_accounts(block, true)
+            .into_iter()
+            .filter_map(|bytes| AccountIdentifier::from_slice(bytes).ok())
+            .filter_map(|account| ids.get(&account).copied())
+            .collect();
+        user_ids.sort();
+        user_ids.dedup();
+        user_ids
+    })
+}
+
+fn poll_ledger(job: &str) {
+    ic_cdk::spawn(ingestion::ingest(job.to_string()));
+    ic_cdk::spawn(backfill::run(job.to_string()));
+    if let Err(e) = refresh::start(job) {
+        jobs::report_error::<Task>(job, e.message);
+    }
 }
 
 #[ic_cdk::init]
-async fn init() {
+fn init(args: Option<InitArgs>) {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    let seconds = 15;
-    INTERVAL_IN_SECONDS.with(|interval_ref| {
-        interval_ref.replace(seconds);
-    });
+    if let Some(args) = args {
+        config::apply(args);
+    }
+
+    let schedule = Schedule::Interval {
+        seconds: DEFAULT_INTERVAL_IN_SECONDS,
+    };
+    jobs::set(DEFAULT_JOB.to_string(), Task::PollLedger, schedule).unwrap();
+
+    count_instructions(start_instructions, "init".to_string());
+}
+
+fn save_state() -> StableState {
+    StableState {
+        version: STATE_VERSION,
+        jobs: Some(jobs::save()),
+        users: Some(USERS.with(|users| users.borrow().clone())),
+        admins: Some(admin::list()),
+        interval_settings: Some(interval::save()),
+        refresh: Some(refresh::save()),
+        cycles_reserve: Some(cycles::reserve()),
+        history: Some(history::save()),
+        ledger_config: Some(config::get()),
+        ingestion: Some(ingestion::save()),
+        transactions: Some(transactions::save()),
+        alerts: Some(alerts::save()),
+        backfill: Some(backfill::save()),
+        backfilled_blocks: Some(transactions::save_backfilled()),
+    }
+}
+
+// Restores the saved state, handing back the jobs to re-arm
+fn restore_state(state: StableState) -> JobStore<Task> {
+    let users = state.users.unwrap_or_default();
+    leaderboard::rebuild(users.iter().map(|(id, user)| (*id, user.balance)));
+    let user_ids = users
+        .iter()
+        .map(|(id, user)| (user.account(), *id))
+        .collect();
+    USER_IDS.with(|ids| ids.replace(user_ids));
+    let account_ids = users
+        .iter()
+        .map(|(id, user)| (user.account().account_identifier(), *id))
+        .collect();
+    ACCOUNT_IDS.with(|ids| ids.replace(account_ids));
+    USERS.with(|users_ref| *users_ref.borrow_mut() = users);
+    admin::restore(state.admins.unwrap_or_default());
+    interval::restore(state.interval_settings.unwrap_or_default());
+    if let Some(reserve) = state.cycles_reserve {
+        cycles::set_reserve(reserve);
+    }
+    history::restore(state.history.unwrap_or_default());
+    config::set(state.ledger_config.unwrap_or_default());
+    ingestion::restore(state.ingestion.unwrap_or_default());
+    // Expects the tracked accounts to be restored already
+    transactions::restore(state.transactions.unwrap_or_default());
+    transactions::restore_backfilled(state.backfilled_blocks.unwrap_or_default());
+    if let Some(alert_state) = state.alerts {
+        alerts::restore(alert_state);
+    }
+    backfill::restore(state.backfill.unwrap_or_default());
+    backfill::enqueue_missing(USERS.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .map(|(id, user)| (*id, user.account().account_identifier()))
+            .collect::<Vec<_>>()
+    }));
+    if let Some(refresh_state) = state.refresh {
+        refresh::restore(refresh_state);
+    }
+    state.jobs.unwrap_or_default()
+}
+
+// The state saved by `pre_upgrade` behind the ingested blocks
+fn decode_state(bytes: &[u8]) -> candid::Result<StableState> {
+    IDLDeserialize::new(bytes)?.get_value()
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // The ingested blocks already live in stable memory, so the state is saved behind them
+    let bytes = candid::encode_one(save_state()).unwrap();
+    stable::save_state(&bytes).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    // The previous version saved nothing, so there is nothing to lose by starting over
+    if ic_cdk::api::stable::stable_size() == 0 {
+        init(args);
+        return;
+    }
+    let start_instructions = ic_cdk::api::instruction_counter();
 
-    let interval = std::time::Duration::from_secs(seconds);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
-        ic_cdk::spawn(call_query_blocks());
-        USERS.with(|_users| {
-            for user in _users.borrow().values() {
-                ic_cdk::println!("Running from init: {:?}", user.principal);
-                ic_cdk::spawn(update_users(user.clone()));
+    // Trapping rolls the upgrade back rather than dropping the state this version cannot read
+    let state = decode_state(&stable::state_bytes())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode the saved state: {}", e)));
+    if state.version > STATE_VERSION {
+        ic_cdk::trap(&format!(
+            "State version {} is newer than {}",
+            state.version, STATE_VERSION
+        ));
+    }
+    let jobs = restore_state(state);
+    if let Some(args) = args {
+        if let Some(ledger_canister_id) = args.ledger_canister_id {
+            // Trapping rolls the upgrade back, keeping the ledger the blocks came from
+            if let Err(e) = config::ensure_ledger_can_change(ledger_canister_id, blocks_read()) {
+                ic_cdk::trap(&e.message);
             }
-        });
-    });
+        }
+        config::apply(args);
+    }
 
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(timer_id);
-    });
+    // Timers do not survive an upgrade, so re-arm the active jobs on their previous schedule
+    jobs::restore(jobs);
 
-    count_instructions(start_instructions, "init".to_string());
+    // Finish a refresh round that was interrupted by the upgrade
+    refresh::resume();
+
+    count_instructions(start_instructions, "post_upgrade".to_string());
+}
+
+fn current_interval() -> Option<u64> {
+    match jobs::get::<Task>(DEFAULT_JOB).map(|job| job.schedule) {
+        Some(Schedule::Interval { seconds }) => Some(seconds),
+        _ => None,
+    }
 }
 
 #[query]
 fn get_interval() -> Result<u64, Error> {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    let res = INTERVAL_IN_SECONDS.with(|interval_ref| Ok(interval_ref.borrow().clone()));
+    let res = current_interval().ok_or_else(|| Error {
+        message: format!("Job {} does not run on an interval", DEFAULT_JOB),
+    });
 
     count_instructions(start_instructions, "get_interval".to_string());
 
     res
 }
 
+// The interval is checked against the configured bounds by the job registry
 #[update]
 fn set_interval(seconds: u64) -> Result<u64, Error> {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    TIMERS.with(|timers_ref| {
-        let timer_id = timers_ref.borrow().clone();
-        ic_cdk_timers::clear_timer(timer_id);
+    let res = admin::ensure_admin().and_then(|_| {
+        let previous_seconds = current_interval();
+        let schedule = Schedule::Interval { seconds };
+        jobs::set(DEFAULT_JOB.to_string(), Task::PollLedger, schedule)?;
+        interval::record(previous_seconds, seconds);
+        Ok(seconds)
     });
 
-    let interval = std::time::Duration::from_secs(seconds);
-    ic_cdk::println!("Starting a periodic task with interval {:?}", interval);
-    let new_timer_id = ic_cdk_timers::set_timer_interval(interval, || {
-        ic_cdk::spawn(call_query_blocks());
-        USERS.with(|_users| {
-            for user in _users.borrow().values() {
-                ic_cdk::println!("Running from set_interval: {:?}", user.principal);
-                ic_cdk::spawn(update_users(user.clone()));
-            }
-        });
-    });
-    TIMERS.with(|timers_ref| {
-        timers_ref.replace(new_timer_id);
-    });
+    count_instructions(start_instructions, "set_interval".to_string());
+
+    res
+}
+
+#[query]
+fn timer_status() -> Result<TimerStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = jobs::status::<Task>(DEFAULT_JOB);
+
+    count_instructions(start_instructions, "timer_status".to_string());
+
+    res
+}
+
+#[update]
+fn pause_timer() -> Result<TimerStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin()
+        .and_then(|_| jobs::pause::<Task>(DEFAULT_JOB))
+        .and_then(|_| jobs::status::<Task>(DEFAULT_JOB));
+
+    count_instructions(start_instructions, "pause_timer".to_string());
+
+    res
+}
+
+#[update]
+fn resume_timer() -> Result<TimerStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
 
-    INTERVAL_IN_SECONDS.with(|seconds_ref| {
-        seconds_ref.replace(seconds);
+    let res = admin::ensure_admin()
+        .and_then(|_| jobs::resume::<Task>(DEFAULT_JOB))
+        .and_then(|_| jobs::status::<Task>(DEFAULT_JOB));
+
+    count_instructions(start_instructions, "resume_timer".to_string());
+
+    res
+}
+
+#[query]
+fn get_interval_bounds() -> IntervalBounds {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = interval::bounds();
+
+    count_instructions(start_instructions, "get_interval_bounds".to_string());
+
+    res
+}
+
+#[update]
+fn set_interval_bounds(bounds: IntervalBounds) -> Result<IntervalBounds, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| interval::set_bounds(bounds));
+
+    count_instructions(start_instructions, "set_interval_bounds".to_string());
+
+    res
+}
+
+#[query]
+fn cycles_status() -> CyclesStatus {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = cycles::status();
+
+    count_instructions(start_instructions, "cycles_status".to_string());
+
+    res
+}
+
+// Jobs suspended by the cycles guard resume right away if the new reserve is met
+#[update]
+fn set_cycles_reserve(reserve: u128) -> Result<CyclesStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().map(|_| {
+        cycles::set_reserve(reserve);
+        jobs::resume_suspended::<Task>();
+        cycles::status()
     });
 
-    count_instructions(start_instructions, "set_interval".to_string());
+    count_instructions(start_instructions, "set_cycles_reserve".to_string());
+
+    res
+}
 
-    Ok(seconds)
+#[query]
+fn get_ledger_config() -> LedgerConfig {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = config::get();
+
+    count_instructions(start_instructions, "get_ledger_config".to_string());
+
+    res
+}
+
+// Whether blocks of the configured ledger were stored or are being read
+fn blocks_read() -> bool {
+    stable::block_count() > 0
+        || transactions::has_backfilled()
+        || ingestion::in_progress()
+        || backfill::in_progress()
 }
 
 #[update]
-async fn add_user(principal: String) -> Result<String, Error> {
+fn set_ledger_config(ledger_config: LedgerConfig) -> Result<LedgerConfig, Error> {
     let start_instructions = ic_cdk::api::instruction_counter();
 
-    let res = USERS.with(|users| {
-        let mut users = users.borrow_mut();
+    let res = admin::ensure_admin()
+        .and_then(|_| {
+            config::ensure_ledger_can_change(ledger_config.ledger_canister_id, blocks_read())
+        })
+        .map(|_| config::set(ledger_config));
 
-        let new_id = users.len() + 1; // Simple way to generate a new ID
-        let user = User {
-            principal: Principal::from_text(&principal).unwrap(),
-            balance: 1,
-        };
-        users.insert(new_id, user);
+    count_instructions(start_instructions, "set_ledger_config".to_string());
 
-        let principals: Vec<User> = users.values().cloned().collect();
-        let res = serde_json::to_string(&principals).unwrap();
-        Ok(res)
+    res
+}
+
+#[query]
+fn get_block(index: u64) -> Result<Block, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = stable::get_block(index).ok_or_else(|| Error {
+        message: format!("Block {} has not been ingested", index),
     });
 
+    count_instructions(start_instructions, "get_block".to_string());
+
+    res
+}
+
+#[query]
+fn get_block_users(index: u64) -> Result<Vec<usize>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = stable::get_block(index)
+        .map(|block| block_users(&block))
+        .ok_or_else(|| Error {
+            message: format!("Block {} has not been ingested", index),
+        });
+
+    count_instructions(start_instructions, "get_block_users".to_string());
+
+    res
+}
+
+#[query]
+fn account_identifier(principal: Principal, subaccount: Option<Vec<u8>>) -> Result<String, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = Account::new(principal, subaccount)
+        .map(|account| account.account_identifier().to_hex())
+        .map_err(|message| Error { message });
+
+    count_instructions(start_instructions, "account_identifier".to_string());
+
+    res
+}
+
+#[query]
+fn user_by_account(account_identifier: String) -> Result<usize, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = AccountIdentifier::from_hex(&account_identifier)
+        .map_err(|message| Error { message })
+        .and_then(|account| {
+            ACCOUNT_IDS
+                .with(|ids| ids.borrow().get(&account).copied())
+                .ok_or_else(|| Error {
+                    message: format!("Account {} does not belong to a user", account),
+                })
+        });
+
+    count_instructions(start_instructions, "user_by_account".to_string());
+
+    res
+}
+
+#[query]
+fn ingestion_status() -> IngestionStatus {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = ingestion::status();
+
+    count_instructions(start_instructions, "ingestion_status".to_string());
+
+    res
+}
+
+#[query]
+fn backfill_status() -> BackfillStatus {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = backfill::status();
+
+    count_instructions(start_instructions, "backfill_status".to_string());
+
+    res
+}
+
+#[query]
+fn refresh_status() -> RefreshStatus {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = refresh::status();
+
+    count_instructions(start_instructions, "refresh_status".to_string());
+
+    res
+}
+
+#[update]
+fn set_max_in_flight(max_in_flight: u64) -> Result<RefreshStatus, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin()
+        .and_then(|_| refresh::set_max_in_flight(max_in_flight))
+        .map(|_| refresh::status());
+
+    count_instructions(start_instructions, "set_max_in_flight".to_string());
+
+    res
+}
+
+#[query]
+fn get_interval_changes() -> Vec<IntervalChange> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = interval::changes();
+
+    count_instructions(start_instructions, "get_interval_changes".to_string());
+
+    res
+}
+
+#[update]
+fn add_admin(principal: Principal) -> Result<Vec<Principal>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_controller().map(|_| admin::add(principal));
+
+    count_instructions(start_instructions, "add_admin".to_string());
+
+    res
+}
+
+#[update]
+fn remove_admin(principal: Principal) -> Result<Vec<Principal>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_controller().map(|_| admin::remove(&principal));
+
+    count_instructions(start_instructions, "remove_admin".to_string());
+
+    res
+}
+
+#[query]
+fn list_admins() -> Vec<Principal> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::list();
+
+    count_instructions(start_instructions, "list_admins".to_string());
+
+    res
+}
+
+#[update]
+fn register_job(name: String, task: Task, schedule: Schedule) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::register(name, task, schedule));
+
+    count_instructions(start_instructions, "register_job".to_string());
+
+    res
+}
+
+#[query]
+fn list_jobs() -> Vec<Job<Task>> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = jobs::list();
+
+    count_instructions(start_instructions, "list_jobs".to_string());
+
+    res
+}
+
+#[update]
+fn pause_job(name: String) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::pause(&name));
+
+    count_instructions(start_instructions, "pause_job".to_string());
+
+    res
+}
+
+#[update]
+fn resume_job(name: String) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::resume(&name));
+
+    count_instructions(start_instructions, "resume_job".to_string());
+
+    res
+}
+
+#[update]
+fn cancel_job(name: String) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::cancel(&name));
+
+    count_instructions(start_instructions, "cancel_job".to_string());
+
+    res
+}
+
+#[update]
+fn set_missed_tick_policy(name: String, policy: MissedTickPolicy) -> Result<Job<Task>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| jobs::set_missed_tick_policy(&name, policy));
+
+    count_instructions(start_instructions, "set_missed_tick_policy".to_string());
+
+    res
+}
+
+// Accepts a principal or the ICRC-1 textual encoding of an account with a subaccount
+#[update]
+async fn add_user(account: String) -> Result<String, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = Account::from_text(&account)
+        .map_err(|message| Error { message })
+        .and_then(|account| {
+            USERS.with(|users| {
+                let mut users = users.borrow_mut();
+
+                if let Some(user_id) = USER_IDS.with(|ids| ids.borrow().get(&account).copied()) {
+                    return Err(Error {
+                        message: format!("Account {} is already user {}", account, user_id),
+                    });
+                }
+
+                let new_id = users.len() + 1; // Simple way to generate a new ID
+                let user = User {
+                    principal: account.owner,
+                    subaccount: account.subaccount.clone(),
+                    balance: 1,
+                    last_refreshed_at: None,
+                };
+                leaderboard::update(new_id, None, user.balance);
+                history::record(new_id, user.balance, ic_cdk::api::time());
+                let account_id = account.account_identifier();
+                ACCOUNT_IDS.with(|ids| ids.borrow_mut().insert(account_id, new_id));
+                transactions::track(account_id);
+                backfill::enqueue(new_id, account_id);
+                USER_IDS.with(|ids| ids.borrow_mut().insert(account, new_id));
+                users.insert(new_id, user);
+
+                let principals: Vec<User> = users.values().cloned().collect();
+                let res = serde_json::to_string(&principals).unwrap();
+                Ok(res)
+            })
+        });
+
     count_instructions(start_instructions, "add_user".to_string());
 
     res
@@ -339,6 +912,139 @@ fn get_users() -> Result<String, String> {
     res
 }
 
+// Users ranked by ledger balance, highest first
+#[query]
+fn leaderboard(limit: usize) -> Result<String, String> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = USERS.with(|users| {
+        let users = users.borrow();
+
+        let ranking: Vec<&User> = leaderboard::top(limit)
+            .iter()
+            .filter_map(|id| users.get(id))
+            .collect();
+        let res = serde_json::to_string(&ranking).unwrap();
+        Ok(res)
+    });
+
+    count_instructions(start_instructions, "leaderboard".to_string());
+
+    res
+}
+
+// How a user's ledger balance changed between `from` and `to`, in nanoseconds since the epoch
+#[query]
+fn balance_history(
+    user_id: usize,
+    from: u64,
+    to: u64,
+    resolution: Resolution,
+) -> Result<Vec<Bucket>, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = if USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        history::range(user_id, from, to, resolution)
+    } else {
+        Err(Error {
+            message: format!("User {} not found", user_id),
+        })
+    };
+
+    count_instructions(start_instructions, "balance_history".to_string());
+
+    res
+}
+
+// A user's ledger transactions, newest first, in pages of at most `limit` (up to 100)
+#[query]
+fn get_transactions(
+    user_id: usize,
+    cursor: Option<u64>,
+    limit: usize,
+) -> Result<TransactionPage, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = USERS
+        .with(|users| users.borrow().get(&user_id).map(User::account))
+        .map(|account| transactions::page(&account.account_identifier(), cursor, limit))
+        .ok_or_else(|| Error {
+            message: format!("User {} not found", user_id),
+        });
+
+    count_instructions(start_instructions, "get_transactions".to_string());
+
+    res
+}
+
+#[update]
+fn add_alert_rule(
+    user_id: usize,
+    condition: AlertCondition,
+    subscriber: Option<Principal>,
+) -> Result<AlertRule, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| {
+        if USERS.with(|users| users.borrow().contains_key(&user_id)) {
+            alerts::add_rule(user_id, condition, subscriber)
+        } else {
+            Err(Error {
+                message: format!("User {} not found", user_id),
+            })
+        }
+    });
+
+    count_instructions(start_instructions, "add_alert_rule".to_string());
+
+    res
+}
+
+#[update]
+fn remove_alert_rule(rule_id: u64) -> Result<AlertRule, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| alerts::remove_rule(rule_id));
+
+    count_instructions(start_instructions, "remove_alert_rule".to_string());
+
+    res
+}
+
+#[query]
+fn list_alert_rules() -> Vec<AlertRule> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = alerts::rules();
+
+    count_instructions(start_instructions, "list_alert_rules".to_string());
+
+    res
+}
+
+// Triggered alerts, newest first
+#[query]
+fn list_alerts(unacknowledged_only: bool) -> Vec<Alert> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = alerts::alerts(unacknowledged_only);
+
+    count_instructions(start_instructions, "list_alerts".to_string());
+
+    res
+}
+
+#[update]
+fn acknowledge_alert(alert_id: u64) -> Result<Alert, Error> {
+    let start_instructions = ic_cdk::api::instruction_counter();
+
+    let res = admin::ensure_admin().and_then(|_| alerts::acknowledge(alert_id));
+
+    count_instructions(start_instructions, "acknowledge_alert".to_string());
+
+    res
+}
+
 #[query]
 fn search_users(query: String) -> Result<String, String> {
     let start_instructions = ic_cdk::api::instruction_counter();
@@ -346,12 +1052,12 @@ fn search_users(query: String) -> Result<String, String> {
     let res = USERS.with(|users| {
         let users = users.borrow();
 
-        // Filter the users whose principals contain the query string
+        // Filter the users whose accounts contain the query string
         let filtered_principals: Vec<User> = users
             .values()
             .filter(|user| {
-                user.principal
-                    .to_string()
+                user.account()
+                    .to_text()
                     .to_lowercase()
                     .contains(&query.to_lowercase())
             })
@@ -372,3 +1078,84 @@ fn search_users(query: String) -> Result<String, String> {
 
 // Enable Candid export
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canister_common::jobs::JobStatus;
+
+    #[test]
+    fn keeps_polling_after_an_upgrade() {
+        let user = User {
+            principal: Principal::anonymous(),
+            subaccount: Some(vec![1; 32]),
+            balance: 5,
+            last_refreshed_at: Some(3),
+        };
+        let account = Account::new(Principal::anonymous(), Some(vec![1; 32])).unwrap();
+        USERS.with(|users| users.borrow_mut().insert(1, user));
+        let job = Job {
+            name: DEFAULT_JOB.to_string(),
+            task: Task::PollLedger,
+            schedule: Schedule::Interval { seconds: 60 },
+            status: JobStatus::Active,
+            created_at: 0,
+            last_run_at: Some(30),
+            next_run_at: Some(100),
+            run_count: 4,
+            last_error: None,
+            missed_tick_policy: MissedTickPolicy::CatchUp,
+            missed_ticks: 2,
+            drift_nanos: 0,
+        };
+        refresh::set_max_in_flight(7).unwrap();
+        config::apply(InitArgs {
+            ledger_canister_id: Some(Principal::anonymous()),
+            index_canister_id: None,
+        });
+        cycles::set_reserve(5);
+        let mut state = save_state();
+        state.jobs = Some(JobStore::from([(job.name.clone(), job)]));
+        let mut bytes = candid::encode_args((state,)).unwrap();
+        bytes.resize(64 * 1024, 0);
+
+        USERS.with(|users| users.borrow_mut().clear());
+        USER_IDS.with(|ids| ids.borrow_mut().clear());
+        ACCOUNT_IDS.with(|ids| ids.borrow_mut().clear());
+        refresh::restore((50, None, None));
+        config::set(LedgerConfig::default());
+        cycles::set_reserve(0);
+        let jobs = restore_state(decode_state(&bytes).unwrap());
+
+        assert_eq!(USERS.with(|users| users.borrow()[&1].balance), 5);
+        assert_eq!(leaderboard::top(10), [1]);
+        // Balance refreshes find the user by account again
+        let user_id = USER_IDS.with(|ids| ids.borrow().get(&account).copied());
+        assert_eq!(user_id, Some(1));
+        let account_id = AccountIdentifier::new(&Principal::anonymous(), Some(&[1; 32]));
+        let user_id = ACCOUNT_IDS.with(|ids| ids.borrow().get(&account_id).copied());
+        assert_eq!(user_id, Some(1));
+        assert_eq!(cycles::reserve(), 5);
+        let job = &jobs[DEFAULT_JOB];
+        assert!(job.status == JobStatus::Active);
+        assert!(job.schedule == Schedule::Interval { seconds: 60 });
+        assert_eq!(job.run_count, 4);
+        assert!(job.missed_tick_policy == MissedTickPolicy::CatchUp);
+        assert_eq!(job.missed_ticks, 2);
+        // The re-armed job keeps its pending run, or runs right away if that has passed
+        assert_eq!(jobs::due_at(job, 40).unwrap(), 100);
+        assert_eq!(jobs::due_at(job, 140).unwrap(), 140);
+        assert_eq!(refresh::status().max_in_flight, 7);
+        assert_eq!(config::ledger_canister_id(), Principal::anonymous());
+        // Users whose history was never backfilled are queued
+        let (pending, _) = backfill::save();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].user_id, 1);
+    }
+
+    #[test]
+    fn refuses_state_it_cannot_read() {
+        assert!(decode_state(&[0; 64 * 1024]).is_err());
+        assert!(decode_state(b"DIDL\x00\x01\x7d\x07").is_err());
+    }
+}
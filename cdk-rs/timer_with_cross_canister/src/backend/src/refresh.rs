@@ -0,0 +1,295 @@
+use crate::account::Account;
+use crate::{fetch_balance, set_balance, Task, USERS};
+use candid::{CandidType, Deserialize, Principal};
+use canister_common::{jobs, Error};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const DEFAULT_MAX_IN_FLIGHT: u64 = 50;
+
+// Keeps the outstanding calls well within the canister's output queue
+const MAX_IN_FLIGHT_LIMIT: u64 = 500;
+
+pub type RefreshState = (u64, Option<Round>, Option<RoundReport>);
+
+thread_local! {
+    static MAX_IN_FLIGHT: RefCell<u64> = const { RefCell::new(DEFAULT_MAX_IN_FLIGHT) };
+    static ROUND: RefCell<Option<Round>> = RefCell::default();
+    static LAST_ROUND: RefCell<Option<RoundReport>> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RefreshFailure {
+    pub user_id: usize,
+    pub principal: Principal,
+    pub subaccount: Option<Vec<u8>>, // None for the principal's default account
+    pub message: String,
+    pub at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RoundReport {
+    pub job: String,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub refreshed: u64,
+    pub failed: Vec<RefreshFailure>,
+}
+
+// A refresh of every user's balance, one call per user
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Round {
+    next_user_id: Option<usize>, // None once a call has been started for every user
+    in_flight: u64,
+    report: RoundReport,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RefreshStatus {
+    pub max_in_flight: u64,
+    pub in_flight: u64,
+    pub current_round: Option<RoundReport>,
+    pub last_round: Option<RoundReport>,
+}
+
+pub fn max_in_flight() -> u64 {
+    MAX_IN_FLIGHT.with(|max| *max.borrow())
+}
+
+pub fn set_max_in_flight(max_in_flight: u64) -> Result<(), Error> {
+    if max_in_flight == 0 || max_in_flight > MAX_IN_FLIGHT_LIMIT {
+        return Err(Error {
+            message: format!(
+                "Max in-flight calls must be between 1 and {}",
+                MAX_IN_FLIGHT_LIMIT
+            ),
+        });
+    }
+    MAX_IN_FLIGHT.with(|max| max.replace(max_in_flight));
+    // A raised limit applies to the running round right away
+    dispatch();
+    Ok(())
+}
+
+pub fn status() -> RefreshStatus {
+    let round = ROUND.with(|round| round.borrow().clone());
+    RefreshStatus {
+        max_in_flight: max_in_flight(),
+        in_flight: round.as_ref().map_or(0, |round| round.in_flight),
+        current_round: round.map(|round| round.report),
+        last_round: LAST_ROUND.with(|last| last.borrow().clone()),
+    }
+}
+
+// Starts a new round, unless the previous one is still running. A round with no
+// calls outstanding has nothing left to dispatch its remaining users, as when the
+// last call trapped, so the tick picks it up instead.
+pub fn start(job: &str) -> Result<(), Error> {
+    ROUND.with(|round| {
+        let mut round = round.borrow_mut();
+        match round.as_ref() {
+            Some(current) if current.in_flight > 0 => {
+                return Err(Error {
+                    message: format!(
+                        "Skipping tick, the refresh round of job {} started at {} is still running",
+                        current.report.job, current.report.started_at
+                    ),
+                })
+            }
+            Some(_) => return Ok(()),
+            None => {}
+        }
+        *round = Some(Round {
+            next_user_id: Some(0),
+            in_flight: 0,
+            report: RoundReport {
+                job: job.to_string(),
+                started_at: ic_cdk::api::time(),
+                finished_at: None,
+                refreshed: 0,
+                failed: Vec::new(),
+            },
+        });
+        Ok(())
+    })?;
+    dispatch();
+    Ok(())
+}
+
+// Starts calls for the next users until `max_in_flight` are outstanding. Every
+// completed call dispatches again, so the round drains without further timers.
+fn dispatch() {
+    let max_in_flight = max_in_flight();
+    let calls = ROUND.with(|round| {
+        let mut round = round.borrow_mut();
+        let Some(round) = round.as_mut() else {
+            return Vec::new();
+        };
+        USERS.with(|users| {
+            let users = users.borrow();
+            let mut calls = Vec::new();
+            while round.in_flight < max_in_flight {
+                let next = round
+                    .next_user_id
+                    .and_then(|next_user_id| users.range(next_user_id..).next());
+                let Some((user_id, user)) = next else {
+                    round.next_user_id = None;
+                    break;
+                };
+                calls.push((*user_id, user.account(), InFlight::start(round)));
+                round.next_user_id = Some(user_id + 1);
+            }
+            calls
+        })
+    });
+
+    for (user_id, account, call) in calls {
+        ic_cdk::spawn(refresh_user(user_id, account, call));
+    }
+    finish_if_done();
+}
+
+// Counts a balance call as outstanding until it is dropped. A call whose callback
+// traps drops it too, when the cleanup drops the call's future.
+struct InFlight;
+
+impl InFlight {
+    fn start(round: &mut Round) -> Self {
+        round.in_flight += 1;
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        ROUND.with(|round| {
+            if let Some(round) = round.borrow_mut().as_mut() {
+                round.in_flight = round.in_flight.saturating_sub(1);
+            }
+        });
+    }
+}
+
+async fn refresh_user(user_id: usize, account: Account, call: InFlight) {
+    let result = fetch_balance(account.clone()).await;
+
+    let now = ic_cdk::api::time();
+    let failure = match result {
+        Ok(balance) => {
+            set_balance(user_id, balance, now);
+            None
+        }
+        Err(message) => Some(RefreshFailure {
+            user_id,
+            principal: account.owner,
+            subaccount: account.subaccount,
+            message,
+            at: now,
+        }),
+    };
+
+    let job = ROUND.with(|round| {
+        let mut round = round.borrow_mut();
+        let round = round.as_mut()?;
+        match &failure {
+            Some(failure) => round.report.failed.push(failure.clone()),
+            None => round.report.refreshed += 1,
+        }
+        Some(round.report.job.clone())
+    });
+    if let (Some(job), Some(failure)) = (job, failure) {
+        let msg = format!("Refreshing user {} failed: {}", user_id, failure.message);
+        jobs::report_error::<Task>(&job, msg);
+    }
+
+    drop(call);
+    dispatch();
+}
+
+fn finish_if_done() {
+    let report = ROUND.with(|round| {
+        let mut round = round.borrow_mut();
+        match round.as_ref() {
+            Some(current) if current.next_user_id.is_none() && current.in_flight == 0 => {
+                round.take().map(|round| round.report)
+            }
+            _ => None,
+        }
+    });
+    if let Some(mut report) = report {
+        report.finished_at = Some(ic_cdk::api::time());
+        LAST_ROUND.with(|last| last.replace(Some(report)));
+    }
+}
+
+pub fn save() -> RefreshState {
+    (
+        max_in_flight(),
+        ROUND.with(|round| round.borrow().clone()),
+        LAST_ROUND.with(|last| last.borrow().clone()),
+    )
+}
+
+// Calls outstanding during an upgrade never complete, so their users are skipped
+pub fn restore((max_in_flight, round, last_round): RefreshState) {
+    MAX_IN_FLIGHT.with(|max| max.replace(max_in_flight));
+    LAST_ROUND.with(|last| last.replace(last_round));
+    let round = round.map(|round| Round {
+        in_flight: 0,
+        ..round
+    });
+    ROUND.with(|round_ref| round_ref.replace(round));
+}
+
+// Dispatches the rest of a round interrupted by an upgrade once the upgrade is done
+pub fn resume() {
+    if ROUND.with(|round| round.borrow().is_some()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, dispatch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(in_flight: u64) -> Round {
+        Round {
+            next_user_id: Some(5),
+            in_flight,
+            report: RoundReport {
+                job: "poll_ledger".to_string(),
+                started_at: 0,
+                finished_at: None,
+                refreshed: 4,
+                failed: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn releases_a_call_when_it_is_dropped() {
+        ROUND.with(|round_ref| round_ref.replace(Some(round(0))));
+        let calls: Vec<InFlight> = ROUND.with(|round| {
+            let mut round = round.borrow_mut();
+            let round = round.as_mut().unwrap();
+            vec![InFlight::start(round), InFlight::start(round)]
+        });
+        assert_eq!(status().in_flight, 2);
+
+        // As when the callback of one call traps and its future is dropped
+        drop(calls);
+        assert_eq!(status().in_flight, 0);
+        assert!(status().current_round.is_some());
+    }
+
+    #[test]
+    fn skips_the_calls_outstanding_during_an_upgrade() {
+        restore((7, Some(round(3)), None));
+
+        let status = status();
+        assert_eq!(status.max_in_flight, 7);
+        assert_eq!(status.in_flight, 0);
+        assert_eq!(status.current_round.map(|report| report.refreshed), Some(4));
+    }
+}
@@ -0,0 +1,97 @@
+// Stable memory layout:
+//
+//   [0, 8)                        number of stored blocks
+//   [8, 8 + n * BLOCK_SLOT_BYTES) ingested ledger blocks, block i in slot i
+//   [end of the blocks, ...)      heap state saved by `pre_upgrade`
+//
+// Blocks are written as they are ingested and outlive upgrades in place, while the
+// heap state is only written during an upgrade and read back right after it. The
+// snapshot therefore sits behind the blocks and is overwritten by the next blocks.
+use crate::Block;
+use candid::{Decode, Encode};
+use canister_common::Error;
+use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
+
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+const HEADER_BYTES: u64 = 8;
+
+// Encoded blocks take less than half a slot, each slot starting with the encoded length
+const BLOCK_SLOT_BYTES: u64 = 1024;
+const LENGTH_BYTES: u64 = 4;
+
+fn ensure_capacity(end: u64) -> Result<(), Error> {
+    let capacity = stable64_size() * WASM_PAGE_BYTES;
+    if end > capacity {
+        let pages = (end - capacity).div_ceil(WASM_PAGE_BYTES);
+        stable64_grow(pages).map_err(|e| Error {
+            message: format!("Failed to grow stable memory: {}", e),
+        })?;
+    }
+    Ok(())
+}
+
+fn read_u64(offset: u64) -> u64 {
+    let mut buf = [0; 8];
+    stable64_read(offset, &mut buf);
+    u64::from_le_bytes(buf)
+}
+
+fn slot_offset(index: u64) -> u64 {
+    HEADER_BYTES + index * BLOCK_SLOT_BYTES
+}
+
+pub fn block_count() -> u64 {
+    if stable64_size() == 0 {
+        return 0;
+    }
+    read_u64(0)
+}
+
+// Stores the block at the next index, which is returned
+pub fn append_block(block: &Block) -> Result<u64, Error> {
+    let bytes = Encode!(block).map_err(|e| Error {
+        message: format!("Failed to encode block: {}", e),
+    })?;
+    if LENGTH_BYTES + bytes.len() as u64 > BLOCK_SLOT_BYTES {
+        return Err(Error {
+            message: format!("Encoded block of {} bytes exceeds its slot", bytes.len()),
+        });
+    }
+
+    let index = block_count();
+    let offset = slot_offset(index);
+    ensure_capacity(offset + BLOCK_SLOT_BYTES)?;
+    stable64_write(offset, &(bytes.len() as u32).to_le_bytes());
+    stable64_write(offset + LENGTH_BYTES, &bytes);
+    stable64_write(0, &(index + 1).to_le_bytes());
+    Ok(index)
+}
+
+pub fn get_block(index: u64) -> Option<Block> {
+    if index >= block_count() {
+        return None;
+    }
+    let offset = slot_offset(index);
+    let mut length = [0; LENGTH_BYTES as usize];
+    stable64_read(offset, &mut length);
+    let mut bytes = vec![0; u32::from_le_bytes(length) as usize];
+    stable64_read(offset + LENGTH_BYTES, &mut bytes);
+    Decode!(&bytes, Block).ok()
+}
+
+// Writes the encoded heap state behind the blocks, prefixed with its length
+pub fn save_state(bytes: &[u8]) -> Result<(), Error> {
+    let offset = slot_offset(block_count());
+    ensure_capacity(offset + 8 + bytes.len() as u64)?;
+    stable64_write(offset, &(bytes.len() as u64).to_le_bytes());
+    stable64_write(offset + 8, bytes);
+    Ok(())
+}
+
+// The encoded heap state written by `save_state`
+pub fn state_bytes() -> Vec<u8> {
+    let offset = slot_offset(block_count());
+    let mut bytes = vec![0; read_u64(offset) as usize];
+    stable64_read(offset + 8, &mut bytes);
+    bytes
+}
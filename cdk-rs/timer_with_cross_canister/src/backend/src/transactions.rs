@@ -0,0 +1,330 @@
+use crate::account::{block_accounts, AccountIdentifier};
+use crate::{is_tracked, stable, Block, Operation};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+// Longest page a single query returns
+pub const MAX_LIMIT: usize = 100;
+
+// Stored blocks read per message when indexing the earlier blocks of a new account
+const RESCAN_BLOCKS_PER_MESSAGE: u64 = 2_000;
+
+// Indices of the ingested blocks that move funds of a tracked account or approve a
+// spender on its behalf, keyed by the account identifier's bytes, and the accounts
+// whose earlier blocks are still being indexed
+pub type TransactionIndex = (BTreeMap<Vec<u8>, BTreeSet<u64>>, VecDeque<Rescan>);
+
+// Blocks backfilled from the index canister that have not been ingested yet
+pub type BackfilledBlocks = BTreeMap<u64, Block>;
+
+thread_local! {
+    static INDEX: RefCell<BTreeMap<Vec<u8>, BTreeSet<u64>>> = RefCell::default();
+    // Dropped as ingestion reaches them, the stored block taking their place
+    static BACKFILLED: RefCell<BackfilledBlocks> = RefCell::default();
+    static RESCANS: RefCell<VecDeque<Rescan>> = RefCell::default();
+}
+
+// Stored blocks of an account that was added after they were ingested, still to be
+// indexed from `next_block_index` up to `end`
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Rescan {
+    account: Vec<u8>,
+    next_block_index: u64,
+    end: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    Transfer,
+    Mint,
+    Burn,
+    Approve,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+// A block as seen from one of its accounts
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AccountTransaction {
+    pub block_index: u64,
+    pub timestamp: u64,
+    pub kind: TransactionKind,
+    pub direction: Direction,
+    pub counterparty: Option<String>, // Hex account identifier, None for mints and burns
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub memo: u64,
+    pub icrc1_memo: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct TransactionPage {
+    pub transactions: Vec<AccountTransaction>,
+    pub next_cursor: Option<u64>, // Pass as `cursor` to read the following, older page
+}
+
+fn insert(account: &[u8], block_index: u64) {
+    INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .entry(account.to_vec())
+            .or_default()
+            .insert(block_index)
+    });
+}
+
+// Lists the block in the history of its tracked accounts
+pub fn index(block_index: u64, block: &Block) {
+    for account in block_accounts(block, false) {
+        if is_tracked(account) {
+            insert(account, block_index);
+        }
+    }
+    BACKFILLED.with(|backfilled| backfilled.borrow_mut().remove(&block_index));
+}
+
+// Lists a block fetched from the index canister until ingestion stores it
+pub fn backfill(block_index: u64, block: Block) {
+    if block_index < stable::block_count() {
+        return;
+    }
+    for account in block_accounts(&block, false) {
+        if is_tracked(account) {
+            insert(account, block_index);
+        }
+    }
+    BACKFILLED.with(|backfilled| backfilled.borrow_mut().insert(block_index, block));
+}
+
+// Indexes the blocks of a newly tracked account. The backfilled blocks are indexed
+// right away, while the stored ones are read in the background by zero-delay timers,
+// as they may be too many for a single message.
+pub fn track(account: AccountIdentifier) {
+    let account = account.as_bytes();
+    BACKFILLED.with(|backfilled| {
+        for (block_index, block) in backfilled.borrow().iter() {
+            if block_accounts(block, false).contains(&account) {
+                insert(account, *block_index);
+            }
+        }
+    });
+
+    let end = stable::block_count();
+    if end == 0 {
+        return;
+    }
+    let idle = RESCANS.with(|rescans| {
+        let mut rescans = rescans.borrow_mut();
+        rescans.push_back(Rescan {
+            account: account.to_vec(),
+            next_block_index: 0,
+            end,
+        });
+        rescans.len() == 1
+    });
+    if idle {
+        ic_cdk_timers::set_timer(Duration::ZERO, rescan);
+    }
+}
+
+// Indexes the next stored blocks of the account at the front of the queue
+fn rescan() {
+    let Some(mut front) = RESCANS.with(|rescans| rescans.borrow().front().cloned()) else {
+        return;
+    };
+    let end = front
+        .end
+        .min(front.next_block_index + RESCAN_BLOCKS_PER_MESSAGE);
+    for block_index in front.next_block_index..end {
+        let Some(block) = stable::get_block(block_index) else {
+            continue;
+        };
+        if block_accounts(&block, false).contains(&front.account.as_slice()) {
+            insert(&front.account, block_index);
+        }
+    }
+    front.next_block_index = end;
+
+    let more = RESCANS.with(|rescans| {
+        let mut rescans = rescans.borrow_mut();
+        rescans.pop_front();
+        if front.next_block_index < front.end {
+            rescans.push_front(front);
+        }
+        !rescans.is_empty()
+    });
+    if more {
+        ic_cdk_timers::set_timer(Duration::ZERO, rescan);
+    }
+}
+
+pub fn has_backfilled() -> bool {
+    BACKFILLED.with(|backfilled| !backfilled.borrow().is_empty())
+}
+
+fn get_block(block_index: u64) -> Option<Block> {
+    stable::get_block(block_index)
+        .or_else(|| BACKFILLED.with(|backfilled| backfilled.borrow().get(&block_index).cloned()))
+}
+
+// Describes the block from the side of `account`, which must be one of its parties
+fn view(block_index: u64, block: &Block, account: &[u8]) -> Option<AccountTransaction> {
+    // The counterparty of an outgoing operation is its second party, and vice versa.
+    // A transfer to oneself is listed once, as outgoing since it pays the fee.
+    let (kind, from, to, amount, fee) = match block.transaction.operation.as_ref()? {
+        Operation::Transfer(transfer) => (
+            TransactionKind::Transfer,
+            Some(transfer.from.as_slice()),
+            Some(transfer.to.as_slice()),
+            transfer.amount.e8s,
+            Some(transfer.fee.e8s),
+        ),
+        Operation::Mint(mint) => (
+            TransactionKind::Mint,
+            None,
+            Some(mint.to.as_slice()),
+            mint.amount.e8s,
+            None,
+        ),
+        Operation::Burn(burn) => (
+            TransactionKind::Burn,
+            Some(burn.from.as_slice()),
+            None,
+            burn.amount.e8s,
+            None,
+        ),
+        Operation::Approve(approve) => (
+            TransactionKind::Approve,
+            Some(approve.from.as_slice()),
+            Some(approve.spender.as_slice()),
+            approve.allowance.e8s,
+            Some(approve.fee.e8s),
+        ),
+    };
+    let (direction, counterparty) = if from == Some(account) {
+        (Direction::Out, to)
+    } else {
+        (Direction::In, from)
+    };
+    Some(AccountTransaction {
+        block_index,
+        timestamp: block.timestamp.timestamp_nanos,
+        kind,
+        direction,
+        counterparty: counterparty.map(hex::encode),
+        amount,
+        fee,
+        memo: block.transaction.memo,
+        icrc1_memo: block.transaction.icrc1_memo.clone(),
+    })
+}
+
+// The account's transactions, newest first, starting below block `cursor` if given
+pub fn page(account: &AccountIdentifier, cursor: Option<u64>, limit: usize) -> TransactionPage {
+    let limit = limit.min(MAX_LIMIT);
+    let key = account.as_bytes();
+    let (block_indices, more) = INDEX.with(|index| {
+        let index = index.borrow();
+        let Some(all) = index.get(key) else {
+            return (Vec::new(), false);
+        };
+        let mut newest: Vec<u64> = all
+            .range(..cursor.unwrap_or(u64::MAX))
+            .rev()
+            .take(limit + 1)
+            .copied()
+            .collect();
+        let more = newest.len() > limit;
+        newest.truncate(limit);
+        (newest, more)
+    });
+
+    let transactions: Vec<AccountTransaction> = block_indices
+        .iter()
+        .filter_map(|i| view(*i, &get_block(*i)?, key))
+        .collect();
+    TransactionPage {
+        next_cursor: if more {
+            block_indices.last().copied()
+        } else {
+            None
+        },
+        transactions,
+    }
+}
+
+pub fn save() -> TransactionIndex {
+    (
+        INDEX.with(|index| index.borrow().clone()),
+        RESCANS.with(|rescans| rescans.borrow().clone()),
+    )
+}
+
+pub fn restore((index, rescans): TransactionIndex) {
+    INDEX.with(|index_ref| index_ref.replace(index));
+    if !rescans.is_empty() {
+        ic_cdk_timers::set_timer(Duration::ZERO, rescan);
+    }
+    RESCANS.with(|rescans_ref| rescans_ref.replace(rescans));
+}
+
+pub fn save_backfilled() -> BackfilledBlocks {
+    BACKFILLED.with(|backfilled| backfilled.borrow().clone())
+}
+
+pub fn restore_backfilled(backfilled: BackfilledBlocks) {
+    BACKFILLED.with(|backfilled_ref| backfilled_ref.replace(backfilled));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{E8s, Timestamp, Transaction, Transfer, ACCOUNT_IDS};
+    use candid::Principal;
+
+    fn transfer(
+        from: &AccountIdentifier,
+        to: &AccountIdentifier,
+        spender: &AccountIdentifier,
+    ) -> Block {
+        Block {
+            transaction: Transaction {
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer(Transfer {
+                    to: to.as_bytes().to_vec(),
+                    fee: E8s { e8s: 10_000 },
+                    from: from.as_bytes().to_vec(),
+                    amount: E8s { e8s: 1 },
+                    spender: Some(spender.as_bytes().to_vec()),
+                })),
+                created_at_time: Timestamp { timestamp_nanos: 0 },
+            },
+            timestamp: Timestamp { timestamp_nanos: 0 },
+            parent_hash: None,
+        }
+    }
+
+    #[test]
+    fn indexes_tracked_accounts_only() {
+        let [tracked, untracked, spender] = [[1; 32], [2; 32], [3; 32]]
+            .map(|subaccount| AccountIdentifier::new(&Principal::anonymous(), Some(&subaccount)));
+        ACCOUNT_IDS.with(|ids| ids.borrow_mut().extend([(tracked, 1), (spender, 2)]));
+        let block = transfer(&tracked, &untracked, &spender);
+        assert_eq!(block_accounts(&block, false).len(), 2);
+        assert_eq!(block_accounts(&block, true).len(), 3);
+
+        index(7, &block);
+        let (index, ..) = save();
+        // The spender only acts on behalf of `from`, so the block is not in its history
+        assert_eq!(index.keys().collect::<Vec<_>>(), vec![tracked.as_bytes()]);
+        assert!(index[tracked.as_bytes()].contains(&7));
+    }
+}